@@ -0,0 +1,14 @@
+CREATE TABLE jobs (
+  id BIGSERIAL PRIMARY KEY,
+  kind VARCHAR NOT NULL,
+  request JSONB NOT NULL,
+  status VARCHAR NOT NULL DEFAULT 'queued',
+  device VARCHAR,
+  started_at TIMESTAMP,
+  finished_at TIMESTAMP,
+  error TEXT,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX jobs_status_idx ON jobs (status);
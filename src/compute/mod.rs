@@ -0,0 +1,613 @@
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt;
+use std::sync::Arc;
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor::DescriptorBufferDesc;
+use vulkano::descriptor::descriptor::DescriptorDesc;
+use vulkano::descriptor::descriptor::DescriptorDescTy;
+use vulkano::descriptor::descriptor::ShaderStages;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::pipeline_layout::PipelineLayout;
+use vulkano::descriptor::pipeline_layout::PipelineLayoutDesc;
+use vulkano::descriptor::pipeline_layout::PipelineLayoutDescPcRange;
+use vulkano::descriptor::DescriptorSet;
+use vulkano::pipeline::shader::ShaderModule;
+use vulkano::pipeline::ComputePipeline;
+use vulkano::sync::now;
+use vulkano::sync::GpuFuture;
+
+use crate::context::GpuContext;
+use crate::device::{DeviceSelector, QueueKind, SelectionError};
+use crate::jobs::{Interruption, JobControl};
+use crate::shaders::{le_words, Compilation};
+
+mod reflect;
+
+/// Upper bound on the buffers of one job. Vulkan only guarantees four storage buffers per
+/// shader stage, so the device limit is checked as well.
+pub const MAX_BUFFERS: usize = 8;
+
+/// A compute job: a GLSL compute shader, how many workgroups to dispatch and its buffers.
+///
+/// Buffer `i` of `buffers` is bound as a storage buffer at `layout(set = 0, binding = i)`, so
+/// the shader must declare exactly those bindings.
+#[derive(Deserialize, Serialize)]
+pub struct ComputeRequest {
+    pub source: String,
+    pub workgroups: [u32; 3],
+    pub buffers: Vec<BufferSpec>,
+    #[serde(default)]
+    pub device: Option<DeviceSelector>,
+    #[serde(default)]
+    pub output: OutputEncoding,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct BufferSpec {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: ElementType,
+    /// Initial content. Buffers the shader only writes to can give `len` instead.
+    #[serde(default)]
+    pub data: Option<BufferData>,
+    /// Number of zeroed elements, for buffers without `data`.
+    #[serde(default)]
+    pub len: Option<usize>,
+    /// Whether the content is returned once the shader has run.
+    #[serde(default)]
+    pub output: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ElementType {
+    F32,
+    I32,
+    U32,
+}
+
+/// Buffer content, either as a JSON array of numbers or as a base64 string of little-endian
+/// elements.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum BufferData {
+    Values(Vec<f64>),
+    Base64(String),
+}
+
+/// How output buffers are sent back.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputEncoding {
+    /// JSON arrays of numbers.
+    Json,
+    /// Base64 strings of little-endian elements inside the JSON response.
+    Base64,
+    /// The raw bytes of the single output buffer as `application/octet-stream`.
+    Binary,
+}
+
+impl Default for OutputEncoding {
+    fn default() -> OutputEncoding {
+        OutputEncoding::Json
+    }
+}
+
+/// The buffers marked as outputs, as raw 32-bit words, in binding order.
+pub struct ComputeOutput {
+    pub buffers: Vec<RawBuffer>,
+}
+
+pub struct RawBuffer {
+    pub name: String,
+    pub ty: ElementType,
+    pub words: Vec<u32>,
+}
+
+impl ComputeOutput {
+    /// Encodes the buffers for a JSON response.
+    pub fn into_result(self, encoding: OutputEncoding) -> ComputeResult {
+        let buffers = self
+            .buffers
+            .into_iter()
+            .map(|buffer| OutputBuffer {
+                data: output_data(buffer.ty, &buffer.words, encoding),
+                name: buffer.name,
+                ty: buffer.ty,
+            })
+            .collect();
+        ComputeResult { buffers }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ComputeResult {
+    pub buffers: Vec<OutputBuffer>,
+}
+
+#[derive(Serialize)]
+pub struct OutputBuffer {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: ElementType,
+    pub data: OutputData,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum OutputData {
+    F32(Vec<f32>),
+    I32(Vec<i32>),
+    U32(Vec<u32>),
+    Base64(String),
+}
+
+#[derive(Debug)]
+pub enum ComputeError {
+    /// The request itself doesn't make sense.
+    Invalid(String),
+    Selection(SelectionError),
+    /// Vulkan failed while running a valid request.
+    Vulkan(String),
+    Interrupted(Interruption),
+}
+
+impl fmt::Display for ComputeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ComputeError::Invalid(message) => write!(f, "{}", message),
+            ComputeError::Selection(err) => write!(f, "{}", err),
+            ComputeError::Vulkan(message) => write!(f, "vulkan error: {}", message),
+            ComputeError::Interrupted(interruption) => write!(f, "{}", interruption),
+        }
+    }
+}
+
+impl From<SelectionError> for ComputeError {
+    fn from(err: SelectionError) -> ComputeError {
+        ComputeError::Selection(err)
+    }
+}
+
+impl From<Interruption> for ComputeError {
+    fn from(interruption: Interruption) -> ComputeError {
+        ComputeError::Interrupted(interruption)
+    }
+}
+
+fn vulkan<E: Error>(err: E) -> ComputeError {
+    ComputeError::Vulkan(err.to_string())
+}
+
+/// Pipeline layout with one storage buffer per binding in set 0, standing in for the layout
+/// `vulkano_shader_derive` would generate from a shader known at compile time. Shaders are only
+/// paired with it once `ComputeRequest::check_shader` has found the same interface in them.
+#[derive(Debug, Clone)]
+pub struct StorageBuffersLayout {
+    buffers: usize,
+}
+
+/// A compute pipeline for a user shader, as cached per device by `GpuContext`.
+pub type ComputePipelineArc = Arc<ComputePipeline<PipelineLayout<StorageBuffersLayout>>>;
+
+unsafe impl PipelineLayoutDesc for StorageBuffersLayout {
+    fn num_sets(&self) -> usize {
+        1
+    }
+
+    fn num_bindings_in_set(&self, set: usize) -> Option<usize> {
+        match set {
+            0 => Some(self.buffers),
+            _ => None,
+        }
+    }
+
+    fn descriptor(&self, set: usize, binding: usize) -> Option<DescriptorDesc> {
+        if set != 0 || binding >= self.buffers {
+            return None;
+        }
+        Some(DescriptorDesc {
+            ty: DescriptorDescTy::Buffer(DescriptorBufferDesc {
+                dynamic: Some(false),
+                storage: true,
+            }),
+            array_count: 1,
+            stages: ShaderStages {
+                compute: true,
+                ..ShaderStages::none()
+            },
+            readonly: false,
+        })
+    }
+
+    fn num_push_constants_ranges(&self) -> usize {
+        0
+    }
+
+    fn push_constants_range(&self, _: usize) -> Option<PipelineLayoutDescPcRange> {
+        None
+    }
+}
+
+fn invalid_buffer(spec: &BufferSpec, message: String) -> ComputeError {
+    ComputeError::Invalid(format!("buffer `{}`: {}", spec.name, message))
+}
+
+/// The 32-bit word holding `value` as an element of type `ty`.
+fn element_word(ty: ElementType, value: f64) -> Result<u32, String> {
+    match ty {
+        ElementType::F32 => Ok((value as f32).to_bits()),
+        ElementType::I32
+            if value.fract() == 0.0
+                && value >= f64::from(i32::min_value())
+                && value <= f64::from(i32::max_value()) =>
+        {
+            Ok(value as i32 as u32)
+        }
+        ElementType::U32
+            if value.fract() == 0.0 && value >= 0.0 && value <= f64::from(u32::max_value()) =>
+        {
+            Ok(value as u32)
+        }
+        ty => Err(format!("{} is not a valid {:?}", value, ty)),
+    }
+}
+
+/// Number of bytes `encoded` decodes to, worked out from its length so that nothing is decoded
+/// before the size has been checked. Characters outside the base64 alphabet are rejected here;
+/// `base64::decode` catches the rest.
+fn decoded_len(encoded: &str) -> Result<usize, String> {
+    let data = encoded.trim_end_matches('=');
+    if encoded.len() - data.len() > 2 {
+        return Err("invalid base64 padding".to_string());
+    }
+    if let Some(c) = data
+        .chars()
+        .find(|&c| !(c.is_ascii_alphanumeric() || c == '+' || c == '/'))
+    {
+        return Err(format!("invalid base64 character {:?}", c));
+    }
+    if data.len() % 4 == 1 {
+        return Err("invalid base64 length".to_string());
+    }
+    Ok(data.len() / 4 * 3 + (data.len() % 4).saturating_sub(1))
+}
+
+/// Number of elements in the buffer `spec` describes, checking its content without building
+/// the buffer.
+fn element_count(spec: &BufferSpec) -> Result<usize, ComputeError> {
+    let count = match (&spec.data, spec.len) {
+        (Some(BufferData::Values(values)), _) => {
+            for &value in values {
+                element_word(spec.ty, value).map_err(|e| invalid_buffer(spec, e))?;
+            }
+            values.len()
+        }
+        (Some(BufferData::Base64(encoded)), _) => {
+            let bytes = decoded_len(encoded).map_err(|e| invalid_buffer(spec, e))?;
+            if bytes % 4 != 0 {
+                return Err(invalid_buffer(
+                    spec,
+                    format!("{} bytes is not a whole number of 4-byte elements", bytes),
+                ));
+            }
+            bytes / 4
+        }
+        (None, Some(len)) => len,
+        (None, None) => {
+            return Err(invalid_buffer(spec, "needs either `data` or `len`".to_string()));
+        }
+    };
+    if count == 0 {
+        return Err(invalid_buffer(spec, "needs at least one element".to_string()));
+    }
+    Ok(count)
+}
+
+/// Size of the buffer `spec` describes in bytes, saturating for sizes no limit allows anyway.
+fn buffer_bytes(spec: &BufferSpec) -> Result<u64, ComputeError> {
+    let count = element_count(spec)? as u64;
+    Ok(count.saturating_mul(4))
+}
+
+/// Turns a buffer description into the 32-bit words uploaded to the GPU. Only called once
+/// `ComputeRequest::validate` has bounded the size.
+fn initial_words(spec: &BufferSpec) -> Result<Vec<u32>, ComputeError> {
+    match (&spec.data, spec.len) {
+        (Some(BufferData::Values(values)), _) => values
+            .iter()
+            .map(|&value| element_word(spec.ty, value).map_err(|e| invalid_buffer(spec, e)))
+            .collect(),
+        (Some(BufferData::Base64(encoded)), _) => {
+            let bytes = base64::decode(encoded).map_err(|e| invalid_buffer(spec, e.to_string()))?;
+            Ok(le_words(&bytes))
+        }
+        (None, Some(len)) => Ok(vec![0; len]),
+        (None, None) => Err(invalid_buffer(spec, "needs either `data` or `len`".to_string())),
+    }
+}
+
+fn output_data(ty: ElementType, words: &[u32], encoding: OutputEncoding) -> OutputData {
+    if encoding != OutputEncoding::Json {
+        return OutputData::Base64(base64::encode(&words_to_bytes(words)));
+    }
+    match ty {
+        ElementType::F32 => OutputData::F32(words.iter().map(|&w| f32::from_bits(w)).collect()),
+        ElementType::I32 => OutputData::I32(words.iter().map(|&w| w as i32).collect()),
+        ElementType::U32 => OutputData::U32(words.to_vec()),
+    }
+}
+
+pub fn words_to_bytes(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect()
+}
+
+/// `PersistentDescriptorSet`'s builder changes type with every buffer added, so sets with a
+/// runtime number of buffers are spelled out for every count up to `MAX_BUFFERS`.
+macro_rules! storage_set {
+    ($pipeline:expr, $buffers:expr; $($i:expr),*) => {
+        Arc::new(
+            PersistentDescriptorSet::start($pipeline.clone(), 0)
+                $(.add_buffer($buffers[$i].clone()).map_err(vulkan)?)*
+                .build()
+                .map_err(vulkan)?,
+        ) as Arc<dyn DescriptorSet + Send + Sync>
+    };
+}
+
+impl ComputeRequest {
+    /// Checks what can be checked without a device, so that a job which can never run is
+    /// rejected before it is queued. The buffers together may take at most `max_bytes`.
+    pub fn validate(&self, max_bytes: u64) -> Result<(), ComputeError> {
+        if self.buffers.is_empty() || self.buffers.len() > MAX_BUFFERS {
+            return Err(ComputeError::Invalid(format!(
+                "between 1 and {} buffers are supported, got {}",
+                MAX_BUFFERS,
+                self.buffers.len()
+            )));
+        }
+        if self.workgroups.iter().any(|&count| count == 0) {
+            return Err(ComputeError::Invalid(
+                "workgroup counts must be at least 1".to_string(),
+            ));
+        }
+        let outputs = self.buffers.iter().filter(|b| b.output).count();
+        if self.output == OutputEncoding::Binary && outputs != 1 {
+            return Err(ComputeError::Invalid(format!(
+                "binary output needs exactly one output buffer, got {}",
+                outputs
+            )));
+        }
+        let mut total: u64 = 0;
+        for spec in &self.buffers {
+            total = total.saturating_add(buffer_bytes(spec)?);
+        }
+        if total > max_bytes {
+            return Err(ComputeError::Invalid(format!(
+                "the buffers take {} bytes, more than the limit of {}",
+                total, max_bytes
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks that `shader` declares exactly the bindings of `buffers` and nothing else a
+    /// pipeline layout would need, returning its local size.
+    pub fn check_shader(&self, shader: &Compilation) -> Result<[u32; 3], ComputeError> {
+        let spirv = shader.spirv.as_ref().ok_or_else(|| {
+            ComputeError::Invalid("the shader failed to compile".to_string())
+        })?;
+        reflect::check_interface(spirv, self.buffers.len()).map_err(ComputeError::Invalid)
+    }
+}
+
+/// Runs a compute job whose shader has already been compiled, returning the buffers marked as
+/// outputs. The buffers may take `max_bytes` together. Stops waiting for the GPU once `control`
+/// says so.
+pub fn run(
+    context: &GpuContext,
+    request: &ComputeRequest,
+    shader: &Compilation,
+    default_device: &DeviceSelector,
+    max_bytes: u64,
+    control: &JobControl,
+) -> Result<ComputeOutput, ComputeError> {
+    request.validate(max_bytes)?;
+    let local_size = request.check_shader(shader)?;
+    let spirv = shader.spirv.as_ref().ok_or_else(|| {
+        ComputeError::Invalid("the shader failed to compile".to_string())
+    })?;
+
+    let selector = request.device.as_ref().unwrap_or(default_device);
+    let (gpu, queue) = context.device(selector, QueueKind::Compute)?;
+    let device = gpu.device.clone();
+
+    let limits = device.physical_device().limits();
+    let max_counts = limits.max_compute_work_group_count();
+    if (0..3).any(|i| request.workgroups[i] > max_counts[i]) {
+        return Err(ComputeError::Invalid(format!(
+            "workgroups {:?} exceed the device maximum {:?}",
+            request.workgroups, max_counts
+        )));
+    }
+    let max_size = limits.max_compute_work_group_size();
+    if (0..3).any(|i| local_size[i] > max_size[i]) {
+        return Err(ComputeError::Invalid(format!(
+            "local size {:?} exceeds the device maximum {:?}",
+            local_size, max_size
+        )));
+    }
+    let invocations: u64 = local_size.iter().map(|&size| u64::from(size)).product();
+    if invocations > u64::from(limits.max_compute_work_group_invocations()) {
+        return Err(ComputeError::Invalid(format!(
+            "local size {:?} has {} invocations, more than the device maximum of {}",
+            local_size,
+            invocations,
+            limits.max_compute_work_group_invocations()
+        )));
+    }
+    if request.buffers.len() > limits.max_per_stage_descriptor_storage_buffers() as usize {
+        return Err(ComputeError::Invalid(format!(
+            "the device only supports {} storage buffers per shader",
+            limits.max_per_stage_descriptor_storage_buffers()
+        )));
+    }
+    let max_range = u64::from(limits.max_storage_buffer_range());
+    for spec in &request.buffers {
+        let bytes = buffer_bytes(spec)?;
+        if bytes > max_range {
+            return Err(invalid_buffer(
+                spec,
+                format!("{} bytes exceed the device maximum of {}", bytes, max_range),
+            ));
+        }
+    }
+
+    control.check()?;
+    let key = (shader.hash.clone(), request.buffers.len());
+    let pipeline = gpu.compute_pipelines.get_or_build(key, || {
+        // glslang validated the SPIR-V when compiling it, and `check_shader` found exactly the
+        // storage buffers the layout describes and no push constants.
+        let module = unsafe { ShaderModule::new(device.clone(), spirv) }.map_err(vulkan)?;
+        let layout = StorageBuffersLayout {
+            buffers: request.buffers.len(),
+        };
+        let entry_point = unsafe {
+            module.compute_entry_point(CStr::from_bytes_with_nul_unchecked(b"main\0"), layout)
+        };
+        ComputePipeline::new(device.clone(), &entry_point, &())
+            .map(Arc::new)
+            .map_err(vulkan)
+    })?;
+
+    control.check()?;
+    let buffers = request
+        .buffers
+        .iter()
+        .map(initial_words)
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .map(|words| {
+            CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), words.into_iter())
+                .map_err(vulkan)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let set = match buffers.len() {
+        1 => storage_set!(pipeline, buffers; 0),
+        2 => storage_set!(pipeline, buffers; 0, 1),
+        3 => storage_set!(pipeline, buffers; 0, 1, 2),
+        4 => storage_set!(pipeline, buffers; 0, 1, 2, 3),
+        5 => storage_set!(pipeline, buffers; 0, 1, 2, 3, 4),
+        6 => storage_set!(pipeline, buffers; 0, 1, 2, 3, 4, 5),
+        7 => storage_set!(pipeline, buffers; 0, 1, 2, 3, 4, 5, 6),
+        8 => storage_set!(pipeline, buffers; 0, 1, 2, 3, 4, 5, 6, 7),
+        _ => unreachable!("buffer count checked above"),
+    };
+
+    control.check()?;
+    let command_buffer =
+        AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family())
+            .map_err(vulkan)?
+            .dispatch(request.workgroups, pipeline.clone(), set, ())
+            .map_err(vulkan)?
+            .build()
+            .map_err(vulkan)?;
+
+    let future = now(device.clone())
+        .then_execute(queue.clone(), command_buffer)
+        .map_err(vulkan)?
+        .then_signal_fence_and_flush()
+        .map_err(vulkan)?;
+    control.wait(&future)?.map_err(vulkan)?;
+
+    let mut results = Vec::new();
+    for (spec, buffer) in request.buffers.iter().zip(&buffers) {
+        if !spec.output {
+            continue;
+        }
+        let content = buffer.read().map_err(vulkan)?;
+        results.push(RawBuffer {
+            name: spec.name.clone(),
+            ty: spec.ty,
+            words: content.to_vec(),
+        });
+    }
+
+    Ok(ComputeOutput { buffers: results })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(data: Option<BufferData>, len: Option<usize>) -> BufferSpec {
+        BufferSpec {
+            name: "values".to_string(),
+            ty: ElementType::U32,
+            data,
+            len,
+            output: true,
+        }
+    }
+
+    fn request(buffers: Vec<BufferSpec>) -> ComputeRequest {
+        ComputeRequest {
+            source: String::new(),
+            workgroups: [1, 1, 1],
+            buffers,
+            device: None,
+            output: OutputEncoding::Json,
+        }
+    }
+
+    #[test]
+    fn base64_lengths_match_decoding() {
+        for bytes in 0..12 {
+            let encoded = base64::encode(&vec![7u8; bytes]);
+            assert_eq!(decoded_len(&encoded), Ok(bytes), "{}", encoded);
+        }
+        assert!(decoded_len("AAA*").is_err());
+        assert!(decoded_len("A===").is_err());
+        assert!(decoded_len("AAAAA").is_err());
+    }
+
+    #[test]
+    fn elements_are_counted_without_decoding() {
+        let values = buffer(Some(BufferData::Values(vec![1.0, 2.0, 3.0])), None);
+        assert_eq!(element_count(&values).unwrap(), 3);
+        let encoded = buffer(Some(BufferData::Base64(base64::encode(&[0u8; 8]))), None);
+        assert_eq!(element_count(&encoded).unwrap(), 2);
+        assert_eq!(element_count(&buffer(None, Some(1 << 60))).unwrap(), 1 << 60);
+    }
+
+    #[test]
+    fn invalid_buffers_are_rejected() {
+        let negative = buffer(Some(BufferData::Values(vec![-1.0])), None);
+        assert!(element_count(&negative).is_err());
+        let odd = buffer(Some(BufferData::Base64(base64::encode(&[0u8; 6]))), None);
+        assert!(element_count(&odd).is_err());
+        assert!(element_count(&buffer(None, Some(0))).is_err());
+        assert!(element_count(&buffer(None, None)).is_err());
+    }
+
+    #[test]
+    fn total_size_is_capped() {
+        let job = request(vec![buffer(None, Some(16)), buffer(None, Some(16))]);
+        assert!(job.validate(128).is_ok());
+        assert!(job.validate(127).is_err());
+        let huge = request(vec![buffer(None, Some(usize::max_value()))]);
+        assert!(huge.validate(u64::max_value() - 1).is_err());
+    }
+
+    #[test]
+    fn words_are_little_endian() {
+        let encoded = base64::encode(&[1, 0, 0, 0, 0, 1, 0, 0]);
+        let spec = buffer(Some(BufferData::Base64(encoded)), None);
+        assert_eq!(initial_words(&spec).unwrap(), vec![1, 256]);
+        assert_eq!(words_to_bytes(&[1, 256]), vec![1, 0, 0, 0, 0, 1, 0, 0]);
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::shaders::{le_words, SPIRV_MAGIC};
+
+/// The magic number and four more words make up the header of a SPIR-V module.
+const HEADER_WORDS: usize = 5;
+
+// Opcodes, storage classes, decorations and execution models from the SPIR-V specification.
+const OP_ENTRY_POINT: u32 = 15;
+const OP_EXECUTION_MODE: u32 = 16;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+
+const STORAGE_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_UNIFORM: u32 = 2;
+const STORAGE_PUSH_CONSTANT: u32 = 9;
+const STORAGE_STORAGE_BUFFER: u32 = 12;
+
+const DECORATION_BLOCK: u32 = 2;
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+
+const EXECUTION_MODEL_GL_COMPUTE: u32 = 5;
+const EXECUTION_MODE_LOCAL_SIZE: u32 = 17;
+
+/// What a compute shader's SPIR-V declares, as far as the pipeline layout is concerned.
+#[derive(Debug, Default)]
+struct Module {
+    /// Ids of the `GLCompute` entry points named `main`.
+    entry_points: Vec<u32>,
+    local_sizes: HashMap<u32, [u32; 3]>,
+    /// Pointer type ids to their storage class and pointee type.
+    pointers: HashMap<u32, (u32, u32)>,
+    arrays: HashSet<u32>,
+    /// Result id, pointer type and storage class of every global variable.
+    variables: Vec<(u32, u32, u32)>,
+    sets: HashMap<u32, u32>,
+    bindings: HashMap<u32, u32>,
+    blocks: HashSet<u32>,
+    buffer_blocks: HashSet<u32>,
+}
+
+/// Checks that the compute shader `spirv` uses exactly `buffers` storage buffers, at bindings 0
+/// to `buffers - 1` of set 0, and nothing else a pipeline layout would have to describe: no
+/// other descriptors and no push constants. Returns the local size of its `main` entry point.
+pub fn check_interface(spirv: &[u8], buffers: usize) -> Result<[u32; 3], String> {
+    let module = parse(spirv)?;
+    if module.entry_points.len() != 1 {
+        return Err("the shader has no compute entry point named `main`".to_string());
+    }
+    let entry_point = module.entry_points[0];
+    let local_size = *module
+        .local_sizes
+        .get(&entry_point)
+        .ok_or_else(|| "the shader declares no local size".to_string())?;
+
+    let mut bound = vec![false; buffers];
+    for &(id, pointer, storage_class) in &module.variables {
+        if storage_class == STORAGE_PUSH_CONSTANT {
+            return Err("the shader declares push constants, which are not supported".to_string());
+        }
+        if storage_class != STORAGE_UNIFORM_CONSTANT
+            && storage_class != STORAGE_UNIFORM
+            && storage_class != STORAGE_STORAGE_BUFFER
+        {
+            continue;
+        }
+
+        let set = module.sets.get(&id).cloned().unwrap_or(0);
+        let binding = *module
+            .bindings
+            .get(&id)
+            .ok_or_else(|| "the shader declares a resource without a binding".to_string())?;
+        let location = format!("set {}, binding {}", set, binding);
+        let pointee = module.pointers.get(&pointer).map(|&(_, pointee)| pointee);
+        let is_storage_buffer = pointee.map_or(false, |ty| {
+            (storage_class == STORAGE_STORAGE_BUFFER && module.blocks.contains(&ty))
+                || (storage_class == STORAGE_UNIFORM && module.buffer_blocks.contains(&ty))
+        });
+
+        if set != 0 {
+            return Err(format!("{}: only set 0 is supported", location));
+        }
+        if pointee.map_or(false, |ty| module.arrays.contains(&ty)) {
+            return Err(format!("{}: arrays of buffers are not supported", location));
+        }
+        if !is_storage_buffer {
+            return Err(format!("{}: only storage buffers are supported", location));
+        }
+        match bound.get_mut(binding as usize) {
+            Some(taken) if *taken => return Err(format!("{}: bound twice", location)),
+            Some(taken) => *taken = true,
+            None => {
+                return Err(format!(
+                    "{}: there are only {} buffers, at bindings 0 to {}",
+                    location,
+                    buffers,
+                    buffers - 1
+                ));
+            }
+        }
+    }
+
+    if let Some(missing) = bound.iter().position(|&taken| !taken) {
+        return Err(format!(
+            "the shader declares no storage buffer at set 0, binding {}",
+            missing
+        ));
+    }
+    Ok(local_size)
+}
+
+fn parse(spirv: &[u8]) -> Result<Module, String> {
+    let invalid = || "the shader is not a valid SPIR-V module".to_string();
+    if spirv.len() % 4 != 0 {
+        return Err(invalid());
+    }
+    let words = le_words(spirv);
+    if words.len() < HEADER_WORDS || words[0] != SPIRV_MAGIC {
+        return Err(invalid());
+    }
+
+    let mut module = Module::default();
+    let mut position = HEADER_WORDS;
+    while position < words.len() {
+        let count = (words[position] >> 16) as usize;
+        let opcode = words[position] & 0xffff;
+        if count == 0 || position + count > words.len() {
+            return Err(invalid());
+        }
+        let op = &words[position + 1..position + count];
+        position += count;
+
+        match opcode {
+            OP_ENTRY_POINT if op.len() >= 3 => {
+                if op[0] == EXECUTION_MODEL_GL_COMPUTE && literal_string(&op[2..]) == "main" {
+                    module.entry_points.push(op[1]);
+                }
+            }
+            OP_EXECUTION_MODE if op.len() == 5 && op[1] == EXECUTION_MODE_LOCAL_SIZE => {
+                module.local_sizes.insert(op[0], [op[2], op[3], op[4]]);
+            }
+            OP_TYPE_ARRAY | OP_TYPE_RUNTIME_ARRAY if !op.is_empty() => {
+                module.arrays.insert(op[0]);
+            }
+            OP_TYPE_POINTER if op.len() == 3 => {
+                module.pointers.insert(op[0], (op[1], op[2]));
+            }
+            OP_VARIABLE if op.len() >= 3 => {
+                module.variables.push((op[1], op[0], op[2]));
+            }
+            OP_DECORATE if op.len() >= 2 => match (op[1], op.get(2)) {
+                (DECORATION_DESCRIPTOR_SET, Some(&set)) => {
+                    module.sets.insert(op[0], set);
+                }
+                (DECORATION_BINDING, Some(&binding)) => {
+                    module.bindings.insert(op[0], binding);
+                }
+                (DECORATION_BLOCK, _) => {
+                    module.blocks.insert(op[0]);
+                }
+                (DECORATION_BUFFER_BLOCK, _) => {
+                    module.buffer_blocks.insert(op[0]);
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    Ok(module)
+}
+
+/// A nul-terminated UTF-8 string packed into words, lossily decoded.
+fn literal_string(words: &[u32]) -> String {
+    let bytes: Vec<u8> = words
+        .iter()
+        .flat_map(|w| w.to_le_bytes().to_vec())
+        .take_while(|&b| b != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shaders::{self, ShaderStage};
+
+    fn spirv(source: &str) -> Vec<u8> {
+        let compilation = shaders::compile(ShaderStage::Compute, source);
+        match compilation.spirv {
+            Some(spirv) => spirv,
+            None => panic!("{:?}", compilation.diagnostics),
+        }
+    }
+
+    const TWO_BUFFERS: &str = "#version 450
+        layout(local_size_x = 64, local_size_y = 2) in;
+        layout(set = 0, binding = 0) buffer A { float a[]; };
+        layout(set = 0, binding = 1) buffer B { float b[]; };
+        void main() { b[gl_GlobalInvocationID.x] = a[gl_GlobalInvocationID.x]; }";
+
+    #[test]
+    fn matching_shaders_report_their_local_size() {
+        assert_eq!(check_interface(&spirv(TWO_BUFFERS), 2), Ok([64, 2, 1]));
+    }
+
+    #[test]
+    fn buffer_counts_must_match() {
+        let shader = spirv(TWO_BUFFERS);
+        assert!(check_interface(&shader, 1).is_err());
+        assert!(check_interface(&shader, 3).is_err());
+    }
+
+    #[test]
+    fn other_descriptors_are_rejected() {
+        let uniform = spirv(
+            "#version 450
+            layout(local_size_x = 1) in;
+            layout(set = 0, binding = 0) uniform U { float scale; };
+            layout(set = 0, binding = 1) buffer B { float b[]; };
+            void main() { b[0] = scale; }",
+        );
+        assert!(check_interface(&uniform, 2).is_err());
+
+        let other_set = spirv(
+            "#version 450
+            layout(local_size_x = 1) in;
+            layout(set = 1, binding = 0) buffer B { float b[]; };
+            void main() { b[0] = 1.0; }",
+        );
+        assert!(check_interface(&other_set, 1).is_err());
+    }
+
+    #[test]
+    fn push_constants_are_rejected() {
+        let shader = spirv(
+            "#version 450
+            layout(local_size_x = 1) in;
+            layout(push_constant) uniform P { float scale; };
+            layout(set = 0, binding = 0) buffer B { float b[]; };
+            void main() { b[0] = scale; }",
+        );
+        assert!(check_interface(&shader, 1).is_err());
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        assert!(check_interface(&[0; 20], 1).is_err());
+        let mut truncated = spirv(TWO_BUFFERS);
+        truncated.truncate(HEADER_WORDS * 4 + 4);
+        assert!(check_interface(&truncated, 2).is_err());
+    }
+}
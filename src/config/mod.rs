@@ -1,4 +1,656 @@
-#[derive(Deserialize)]
+use clap::ArgMatches;
+use rocket::config::{Config as RocketConfig, ConfigError, Environment, Limits, LoggingLevel};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::str::FromStr;
+
+use crate::device::DeviceSelector;
+use crate::render;
+
+/// Prefix of the environment variables that override configuration values, e.g.
+/// `OPENCL_REST_DATABASE_POOL_SIZE` for `database.pool_size`.
+const ENV_PREFIX: &str = "OPENCL_REST_";
+
+/// Config file read when `--config` is not given. Unlike an explicit path it may be missing.
+const DEFAULT_FILE: &str = "config.toml";
+
+/// Default of `limits.json`, 32 MiB.
+const DEFAULT_JSON_LIMIT: u64 = 32 << 20;
+
+/// Every key the configuration understands, in the dotted form used by the TOML file.
+const KEYS: &[&str] = &[
+  "address",
+  "port",
+  "workers",
+  "log_level",
+  "limits.json",
+  "database.url",
+  "database.pool_size",
+  "database.timeout",
+  "render.width",
+  "render.height",
+  "render.max_width",
+  "render.max_height",
+  "render.device",
+  "shaders.cache_size",
+  "compute.max_bytes",
+  "jobs.workers",
+  "jobs.queue_size",
+  "jobs.retained",
+  "jobs.timeout",
+  "streams.max",
+  "streams.timeout",
+  "sessions.max",
+  "sessions.idle_timeout",
+];
+
 pub struct Config {
-  address: String,
+  pub address: String,
+  pub port: u16,
+  /// Number of Rocket worker threads; Rocket picks one based on the CPU count when unset.
+  pub workers: Option<u16>,
+  pub log_level: LogLevel,
+  /// Largest JSON body accepted, in bytes. Render requests carry textures and models inline, so
+  /// it is well above Rocket's default of 1 MiB.
+  pub json_limit: u64,
+  pub database: DatabaseConfig,
+  pub render: RenderConfig,
+  /// Number of compiled shaders kept in the cache.
+  pub shader_cache_size: usize,
+  pub compute: ComputeConfig,
+  pub jobs: JobsConfig,
+  pub streams: StreamsConfig,
+  pub sessions: SessionsConfig,
+}
+
+impl Config {
+  /// Resolves the configuration from, in order of precedence, the command line, `OPENCL_REST_*`
+  /// environment variables, the `.env` file (which `dotenv()` loads into the environment without
+  /// overriding variables that are already set) and the TOML file. `DATABASE_URL`, from either the
+  /// environment or `.env`, ranks just below `OPENCL_REST_DATABASE_URL`.
+  ///
+  /// Every problem found along the way is collected, so a broken deployment is reported in one go.
+  pub fn load(matches: &ArgMatches) -> Result<Config, ConfigErrors> {
+    let mut errors = Vec::new();
+
+    let mut sources = vec![Source::args(matches), Source::env(), Source::database_url()];
+    match Source::file(matches.value_of("config")) {
+      Ok(Some(file)) => sources.push(file),
+      Ok(None) => {}
+      Err(err) => errors.push(err),
+    }
+
+    let mut resolver = Resolver {
+      sources,
+      errors,
+    };
+
+    let defaults = DatabaseConfig::default();
+    let database = DatabaseConfig {
+      url: resolver.required("database.url").unwrap_or_default(),
+      pool_size: resolver
+        .get("database.pool_size")
+        .unwrap_or(defaults.pool_size),
+      timeout: resolver.get("database.timeout").unwrap_or(defaults.timeout),
+    };
+
+    let defaults = RenderConfig::default();
+    let render = RenderConfig {
+      width: resolver.get("render.width").unwrap_or(defaults.width),
+      height: resolver.get("render.height").unwrap_or(defaults.height),
+      max_width: resolver
+        .get("render.max_width")
+        .unwrap_or(defaults.max_width),
+      max_height: resolver
+        .get("render.max_height")
+        .unwrap_or(defaults.max_height),
+      device: resolver.get("render.device").unwrap_or(defaults.device),
+    };
+
+    let defaults = ComputeConfig::default();
+    let compute = ComputeConfig {
+      max_bytes: resolver
+        .get("compute.max_bytes")
+        .unwrap_or(defaults.max_bytes),
+    };
+
+    let defaults = JobsConfig::default();
+    let jobs = JobsConfig {
+      workers: resolver.get("jobs.workers").unwrap_or(defaults.workers),
+      queue_size: resolver.get("jobs.queue_size").unwrap_or(defaults.queue_size),
+      retained: resolver.get("jobs.retained").unwrap_or(defaults.retained),
+      timeout: resolver.get("jobs.timeout").unwrap_or(defaults.timeout),
+    };
+
+    let defaults = StreamsConfig::default();
+    let streams = StreamsConfig {
+      max: resolver.get("streams.max").unwrap_or(defaults.max),
+      timeout: resolver.get("streams.timeout").unwrap_or(defaults.timeout),
+    };
+
+    let defaults = SessionsConfig::default();
+    let sessions = SessionsConfig {
+      max: resolver.get("sessions.max").unwrap_or(defaults.max),
+      idle_timeout: resolver
+        .get("sessions.idle_timeout")
+        .unwrap_or(defaults.idle_timeout),
+    };
+
+    let config = Config {
+      address: resolver
+        .get("address")
+        .unwrap_or_else(|| "localhost".to_string()),
+      port: resolver.get("port").unwrap_or(8000),
+      workers: resolver.get("workers"),
+      log_level: resolver.get("log_level").unwrap_or_default(),
+      json_limit: resolver.get("limits.json").unwrap_or(DEFAULT_JSON_LIMIT),
+      database,
+      render,
+      shader_cache_size: resolver.get("shaders.cache_size").unwrap_or(256),
+      compute,
+      jobs,
+      streams,
+      sessions,
+    };
+
+    let mut errors = resolver.errors;
+    errors.extend(config.validate());
+    if errors.is_empty() {
+      Ok(config)
+    } else {
+      Err(ConfigErrors(errors))
+    }
+  }
+
+  fn validate(&self) -> Vec<String> {
+    let mut errors = Vec::new();
+    if self.workers == Some(0) {
+      errors.push("workers: must be at least 1".to_string());
+    }
+    if self.json_limit == 0 {
+      errors.push("limits.json: must be at least 1 byte".to_string());
+    }
+    if self.database.pool_size == 0 {
+      errors.push("database.pool_size: must be at least 1".to_string());
+    }
+    if self.database.timeout == 0 {
+      errors.push("database.timeout: must be at least 1 second".to_string());
+    }
+    if self.render.width == 0 || self.render.height == 0 {
+      errors.push("render.width, render.height: must be at least 1".to_string());
+    }
+    if self.render.width > self.render.max_width || self.render.height > self.render.max_height {
+      errors.push(
+        "render.width, render.height: must not exceed render.max_width, render.max_height"
+          .to_string(),
+      );
+    }
+    if self.shader_cache_size == 0 {
+      errors.push("shaders.cache_size: must be at least 1".to_string());
+    }
+    if self.compute.max_bytes == 0 {
+      errors.push("compute.max_bytes: must be at least 1 byte".to_string());
+    }
+    if self.jobs.workers == 0 {
+      errors.push("jobs.workers: must be at least 1".to_string());
+    }
+    if self.jobs.queue_size == 0 {
+      errors.push("jobs.queue_size: must be at least 1".to_string());
+    }
+    if self.jobs.retained == 0 {
+      errors.push("jobs.retained: must be at least 1".to_string());
+    }
+    if self.jobs.timeout == 0 {
+      errors.push("jobs.timeout: must be at least 1 second".to_string());
+    }
+    if self.streams.max == 0 {
+      errors.push("streams.max: must be at least 1".to_string());
+    }
+    if self.streams.timeout == 0 {
+      errors.push("streams.timeout: must be at least 1 second".to_string());
+    }
+    if self.sessions.max == 0 {
+      errors.push("sessions.max: must be at least 1".to_string());
+    }
+    if self.sessions.idle_timeout == 0 {
+      errors.push("sessions.idle_timeout: must be at least 1 second".to_string());
+    }
+    if let Err(err) = self.rocket_config() {
+      errors.push(format!("address: {}", err));
+    }
+    errors
+  }
+
+  /// Turns the server part of the configuration into Rocket's own, so that Rocket.toml and the
+  /// `ROCKET_*` variables no longer apply.
+  pub fn rocket_config(&self) -> Result<RocketConfig, ConfigError> {
+    let environment = Environment::active().unwrap_or(Environment::Development);
+    let mut builder = RocketConfig::build(environment)
+      .address(self.address.as_str())
+      .port(self.port)
+      .log_level(self.log_level.into())
+      .limits(Limits::default().limit("json", self.json_limit));
+    if let Some(workers) = self.workers {
+      builder = builder.workers(workers);
+    }
+    builder.finalize()
+  }
+}
+
+/// Everything that was wrong with the configuration, one problem per line.
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "invalid configuration:")?;
+    for error in &self.0 {
+      writeln!(f, "  - {}", error)?;
+    }
+    Ok(())
+  }
+}
+
+/// Raw values from one place configuration can come from, keyed like `KEYS`.
+struct Source {
+  name: String,
+  values: HashMap<String, String>,
+}
+
+impl Source {
+  fn args(matches: &ArgMatches) -> Source {
+    let flags = [
+      ("address", "address"),
+      ("port", "port"),
+      ("workers", "workers"),
+      ("log_level", "log-level"),
+      ("database.url", "database-url"),
+    ];
+    let values = flags
+      .iter()
+      .filter_map(|&(key, flag)| matches.value_of(flag).map(|v| (key.to_string(), v.to_string())))
+      .collect();
+
+    Source {
+      name: "command line".to_string(),
+      values,
+    }
+  }
+
+  fn env() -> Source {
+    let values = KEYS
+      .iter()
+      .filter_map(|key| {
+        env::var(env_name(key))
+          .ok()
+          .map(|value| (key.to_string(), value))
+      })
+      .collect();
+
+    Source {
+      name: "environment".to_string(),
+      values,
+    }
+  }
+
+  /// `DATABASE_URL` is what the diesel CLI reads, so it is honored like an `OPENCL_REST_*`
+  /// variable, below `OPENCL_REST_DATABASE_URL`.
+  fn database_url() -> Source {
+    let values = env::var("DATABASE_URL")
+      .ok()
+      .map(|url| ("database.url".to_string(), url))
+      .into_iter()
+      .collect();
+
+    Source {
+      name: "DATABASE_URL".to_string(),
+      values,
+    }
+  }
+
+  fn file(path: Option<&str>) -> Result<Option<Source>, String> {
+    let filename = path.unwrap_or(DEFAULT_FILE);
+    let contents = match fs::read_to_string(filename) {
+      Ok(contents) => contents,
+      Err(ref err) if path.is_none() && err.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(err) => return Err(format!("{}: {}", filename, err)),
+    };
+
+    let table = match contents.parse::<toml::Value>() {
+      Ok(toml::Value::Table(table)) => table,
+      Ok(_) => return Err(format!("{}: expected a table", filename)),
+      Err(err) => return Err(format!("{}: {}", filename, err)),
+    };
+
+    let mut values = HashMap::new();
+    let mut errors = Vec::new();
+    flatten(&table, "", &mut values, &mut errors);
+    if !errors.is_empty() {
+      return Err(format!("{}: {}", filename, errors.join("; ")));
+    }
+
+    Ok(Some(Source {
+      name: filename.to_string(),
+      values,
+    }))
+  }
+}
+
+/// Flattens nested TOML tables into dotted keys, rejecting anything not in `KEYS`.
+fn flatten(
+  table: &toml::value::Table,
+  prefix: &str,
+  values: &mut HashMap<String, String>,
+  errors: &mut Vec<String>,
+) {
+  for (name, value) in table {
+    let key = format!("{}{}", prefix, name);
+    let raw = match value {
+      toml::Value::Table(nested) => {
+        flatten(nested, &format!("{}.", key), values, errors);
+        continue;
+      }
+      toml::Value::String(s) => s.clone(),
+      toml::Value::Integer(i) => i.to_string(),
+      toml::Value::Float(f) => f.to_string(),
+      toml::Value::Boolean(b) => b.to_string(),
+      _ => {
+        errors.push(format!("{}: expected a single value", key));
+        continue;
+      }
+    };
+
+    if KEYS.contains(&key.as_str()) {
+      values.insert(key, raw);
+    } else {
+      errors.push(format!("unknown key `{}`", key));
+    }
+  }
+}
+
+fn env_name(key: &str) -> String {
+  format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase())
+}
+
+/// Looks keys up through the sources in order of precedence, recording every bad value.
+struct Resolver {
+  sources: Vec<Source>,
+  errors: Vec<String>,
+}
+
+impl Resolver {
+  fn get<T>(&mut self, key: &str) -> Option<T>
+  where
+    T: FromStr,
+    T::Err: fmt::Display,
+  {
+    let (source, raw) = self
+      .sources
+      .iter()
+      .filter_map(|source| source.values.get(key).map(|raw| (source, raw)))
+      .next()?;
+
+    match raw.parse() {
+      Ok(value) => Some(value),
+      Err(err) => {
+        self.errors.push(format!(
+          "{}: invalid value {:?} from {}: {}",
+          key, raw, source.name, err
+        ));
+        None
+      }
+    }
+  }
+
+  fn required<T>(&mut self, key: &str) -> Option<T>
+  where
+    T: FromStr,
+    T::Err: fmt::Display,
+  {
+    let present = self.sources.iter().any(|s| s.values.contains_key(key));
+    if !present {
+      self.errors.push(format!(
+        "{}: missing, set it in the config file or with {}",
+        key,
+        env_name(key)
+      ));
+    }
+    self.get(key)
+  }
+}
+
+#[derive(Clone, Copy)]
+pub enum LogLevel {
+  Critical,
+  Normal,
+  Debug,
+}
+
+impl Default for LogLevel {
+  fn default() -> LogLevel {
+    LogLevel::Normal
+  }
+}
+
+impl FromStr for LogLevel {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<LogLevel, String> {
+    match s.to_lowercase().as_str() {
+      "critical" => Ok(LogLevel::Critical),
+      "normal" => Ok(LogLevel::Normal),
+      "debug" => Ok(LogLevel::Debug),
+      _ => Err("expected one of critical, normal, debug".to_string()),
+    }
+  }
+}
+
+impl From<LogLevel> for LoggingLevel {
+  fn from(level: LogLevel) -> LoggingLevel {
+    match level {
+      LogLevel::Critical => LoggingLevel::Critical,
+      LogLevel::Normal => LoggingLevel::Normal,
+      LogLevel::Debug => LoggingLevel::Debug,
+    }
+  }
+}
+
+pub struct DatabaseConfig {
+  /// Postgres connection string.
+  pub url: String,
+  /// Maximum number of connections kept open by the pool.
+  pub pool_size: u32,
+  /// Seconds a request waits for a free connection before giving up.
+  pub timeout: u64,
+}
+
+impl Default for DatabaseConfig {
+  fn default() -> DatabaseConfig {
+    DatabaseConfig {
+      url: String::new(),
+      pool_size: 10,
+      timeout: 5,
+    }
+  }
+}
+
+/// Defaults and limits for render requests, kept in Rocket's managed state.
+#[derive(Clone)]
+pub struct RenderConfig {
+  pub width: u32,
+  pub height: u32,
+  /// Largest size a request may ask for, whatever the device would allow.
+  pub max_width: u32,
+  pub max_height: u32,
+  /// Device used by requests that don't pick one themselves.
+  pub device: DeviceSelector,
+}
+
+impl Default for RenderConfig {
+  fn default() -> RenderConfig {
+    RenderConfig {
+      width: render::DEFAULT_DIMENSIONS[0],
+      height: render::DEFAULT_DIMENSIONS[1],
+      max_width: render::DEFAULT_MAX_DIMENSIONS[0],
+      max_height: render::DEFAULT_MAX_DIMENSIONS[1],
+      device: DeviceSelector::Auto,
+    }
+  }
+}
+
+/// Limits for compute jobs, kept in Rocket's managed state.
+#[derive(Clone)]
+pub struct ComputeConfig {
+  /// Largest total size of a job's buffers in bytes, whatever the device would allow.
+  pub max_bytes: u64,
+}
+
+impl Default for ComputeConfig {
+  fn default() -> ComputeConfig {
+    ComputeConfig { max_bytes: 64 << 20 }
+  }
+}
+
+pub struct JobsConfig {
+  /// Number of threads running queued jobs, and so the most jobs that run at once.
+  pub workers: usize,
+  /// Most jobs waiting for a worker; submissions beyond it are turned away.
+  pub queue_size: usize,
+  /// Number of finished jobs whose output is kept in memory.
+  pub retained: usize,
+  /// Seconds a job may run before it is stopped.
+  pub timeout: u64,
+}
+
+impl Default for JobsConfig {
+  fn default() -> JobsConfig {
+    JobsConfig {
+      workers: 2,
+      queue_size: 64,
+      retained: 256,
+      timeout: 60,
+    }
+  }
+}
+
+pub struct StreamsConfig {
+  /// Most frame streams served at once. Each one keeps a Rocket worker busy.
+  pub max: usize,
+  /// Seconds after which a stream is ended.
+  pub timeout: u64,
+}
+
+impl Default for StreamsConfig {
+  fn default() -> StreamsConfig {
+    StreamsConfig { max: 4, timeout: 300 }
+  }
+}
+
+pub struct SessionsConfig {
+  /// Most sessions open at once, each holding its meshes and textures on a device.
+  pub max: usize,
+  /// Seconds without requests after which a session is closed.
+  pub idle_timeout: u64,
+}
+
+impl Default for SessionsConfig {
+  fn default() -> SessionsConfig {
+    SessionsConfig {
+      max: 16,
+      idle_timeout: 300,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn source(name: &str, values: &[(&str, &str)]) -> Source {
+    Source {
+      name: name.to_string(),
+      values: values
+        .iter()
+        .map(|&(key, value)| (key.to_string(), value.to_string()))
+        .collect(),
+    }
+  }
+
+  fn resolver(sources: Vec<Source>) -> Resolver {
+    Resolver {
+      sources,
+      errors: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn earlier_sources_win() {
+    let mut resolver = resolver(vec![
+      source("environment", &[("port", "9000")]),
+      source("DATABASE_URL", &[("database.url", "postgres://env")]),
+      source("config.toml", &[("port", "8080"), ("database.url", "postgres://file")]),
+    ]);
+    assert_eq!(resolver.get::<u16>("port"), Some(9000));
+    assert_eq!(
+      resolver.get::<String>("database.url"),
+      Some("postgres://env".to_string())
+    );
+    assert!(resolver.errors.is_empty());
+  }
+
+  #[test]
+  fn later_sources_fill_in() {
+    let mut resolver = resolver(vec![
+      source("command line", &[]),
+      source("config.toml", &[("jobs.workers", "4")]),
+    ]);
+    assert_eq!(resolver.get::<usize>("jobs.workers"), Some(4));
+    assert_eq!(resolver.get::<usize>("jobs.queue_size"), None);
+  }
+
+  #[test]
+  fn bad_values_name_their_source() {
+    let mut resolver = resolver(vec![source("config.toml", &[("port", "http")])]);
+    assert_eq!(resolver.get::<u16>("port"), None);
+    assert_eq!(resolver.errors.len(), 1);
+    assert!(resolver.errors[0].starts_with("port: invalid value \"http\" from config.toml"));
+  }
+
+  #[test]
+  fn missing_required_keys_are_reported() {
+    let mut resolver = resolver(vec![source("environment", &[])]);
+    assert_eq!(resolver.required::<String>("database.url"), None);
+    assert_eq!(
+      resolver.errors,
+      vec![
+        "database.url: missing, set it in the config file or with OPENCL_REST_DATABASE_URL"
+          .to_string()
+      ]
+    );
+  }
+
+  #[test]
+  fn tables_flatten_into_dotted_keys() {
+    let table = match "port = 8080\n[jobs]\nworkers = 2\nbogus = 1\n"
+      .parse::<toml::Value>()
+      .unwrap()
+    {
+      toml::Value::Table(table) => table,
+      _ => unreachable!(),
+    };
+    let mut values = HashMap::new();
+    let mut errors = Vec::new();
+    flatten(&table, "", &mut values, &mut errors);
+    assert_eq!(values.get("port").map(String::as_str), Some("8080"));
+    assert_eq!(values.get("jobs.workers").map(String::as_str), Some("2"));
+    assert_eq!(errors, vec!["unknown key `jobs.bogus`".to_string()]);
+  }
+
+  #[test]
+  fn env_names_are_prefixed_and_uppercase() {
+    assert_eq!(env_name("database.pool_size"), "OPENCL_REST_DATABASE_POOL_SIZE");
+  }
 }
@@ -0,0 +1,189 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use vulkano::device::Device;
+use vulkano::device::DeviceExtensions;
+use vulkano::device::Queue;
+use vulkano::instance::Instance;
+use vulkano::instance::InstanceCreationError;
+use vulkano::instance::PhysicalDevice;
+use vulkano::instance::QueueFamily;
+
+use crate::compute::ComputePipelineArc;
+use crate::device::{self, DeviceSelector, QueueKind, SelectionError};
+use crate::render::{PipelineKey, RenderPipeline};
+
+/// Vulkan state that outlives requests: the instance, plus every device opened so far along with
+/// its queues and the pipelines built for it.
+///
+/// Created once at startup and shared through Rocket's managed state as an `Arc`, so that
+/// request threads can hold on to it.
+pub struct GpuContext {
+    pub instance: Arc<Instance>,
+    /// Opened devices, keyed by physical device index.
+    devices: Mutex<HashMap<usize, Arc<DeviceContext>>>,
+    /// Number of compute pipelines kept per device.
+    pipeline_cache_size: usize,
+}
+
+/// A logical device with one queue per kind of work it supports.
+pub struct DeviceContext {
+    pub device: Arc<Device>,
+    pub graphics_queue: Option<Arc<Queue>>,
+    pub compute_queue: Option<Arc<Queue>>,
+    /// Render pipelines, built on first use of each key.
+    pub render_pipelines: Mutex<HashMap<PipelineKey, Arc<RenderPipeline>>>,
+    /// Compute pipelines keyed by shader hash and number of buffers.
+    pub compute_pipelines: PipelineCache<(String, usize), ComputePipelineArc>,
+}
+
+impl GpuContext {
+    /// Creates the instance. Each device keeps up to `pipeline_cache_size` compute pipelines.
+    pub fn new(pipeline_cache_size: usize) -> Result<GpuContext, InstanceCreationError> {
+        Ok(GpuContext {
+            instance: device::instance()?,
+            devices: Mutex::new(HashMap::new()),
+            pipeline_cache_size,
+        })
+    }
+
+    /// Returns the device picked by `selector` and its queue for `kind` work, opening the device
+    /// the first time it is asked for.
+    pub fn device(
+        &self,
+        selector: &DeviceSelector,
+        kind: QueueKind,
+    ) -> Result<(Arc<DeviceContext>, Arc<Queue>), SelectionError> {
+        let (physical, _) = device::select(&self.instance, selector, kind)?;
+
+        let context = {
+            let mut devices = self.devices.lock().unwrap();
+            match devices.get(&physical.index()) {
+                Some(context) => context.clone(),
+                None => {
+                    let context =
+                        Arc::new(DeviceContext::open(physical, self.pipeline_cache_size)?);
+                    devices.insert(physical.index(), context.clone());
+                    context
+                }
+            }
+        };
+
+        // `select` only returns devices with a queue family for `kind`, and `open` creates a
+        // queue for each kind the device supports, so this only fails if they disagree.
+        let queue = match kind {
+            QueueKind::Graphics => context.graphics_queue.clone(),
+            QueueKind::Compute => context.compute_queue.clone(),
+        }
+        .ok_or_else(|| SelectionError::NoQueueFamily {
+            selector: selector.clone(),
+            kind,
+        })?;
+
+        Ok((context, queue))
+    }
+}
+
+impl DeviceContext {
+    fn open(
+        physical: PhysicalDevice,
+        pipeline_cache_size: usize,
+    ) -> Result<DeviceContext, SelectionError> {
+        let graphics = physical.queue_families().find(|q| q.supports_graphics());
+        let compute = physical.queue_families().find(|q| q.supports_compute());
+
+        let mut families: Vec<QueueFamily> = Vec::new();
+        for family in graphics.iter().chain(compute.iter()) {
+            if !families.iter().any(|f| f.id() == family.id()) {
+                families.push(*family);
+            }
+        }
+
+        // Enabling everything the device supports means later requests never have to reopen
+        // the device for a feature.
+        let (device, queues) = Device::new(
+            physical,
+            physical.supported_features(),
+            &DeviceExtensions::none(),
+            families.iter().map(|&family| (family, 0.5)),
+        )
+        .map_err(|err| SelectionError::DeviceCreation {
+            device: physical.name().to_string(),
+            reason: err.to_string(),
+        })?;
+
+        let queues: Vec<Arc<Queue>> = queues.collect();
+        let queue_for = |family: Option<QueueFamily>| {
+            family.and_then(|family| {
+                queues
+                    .iter()
+                    .find(|queue| queue.family().id() == family.id())
+                    .cloned()
+            })
+        };
+
+        Ok(DeviceContext {
+            graphics_queue: queue_for(graphics),
+            compute_queue: queue_for(compute),
+            device,
+            render_pipelines: Mutex::new(HashMap::new()),
+            compute_pipelines: PipelineCache::new(pipeline_cache_size),
+        })
+    }
+}
+
+/// Pipelines built on first use of their key. Once `capacity` are stored the oldest one is
+/// dropped, as in `ShaderCache`.
+pub struct PipelineCache<K, V> {
+    capacity: usize,
+    entries: Mutex<PipelineEntries<K, V>>,
+}
+
+struct PipelineEntries<K, V> {
+    by_key: HashMap<K, V>,
+    order: VecDeque<K>,
+}
+
+impl<K, V> PipelineCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn new(capacity: usize) -> PipelineCache<K, V> {
+        PipelineCache {
+            capacity,
+            entries: Mutex::new(PipelineEntries {
+                by_key: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Looks `key` up, building and storing the value on a miss.
+    ///
+    /// The lock is not held while building, so that a slow build doesn't hold up requests for
+    /// other pipelines. Two requests racing for the same key may both build it; the first one
+    /// stored is kept.
+    pub fn get_or_build<E, F>(&self, key: K, build: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+    {
+        if let Some(value) = self.entries.lock().unwrap().by_key.get(&key) {
+            return Ok(value.clone());
+        }
+        let value = build()?;
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(existing) = entries.by_key.get(&key) {
+            return Ok(existing.clone());
+        }
+        while entries.order.len() >= self.capacity.max(1) {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.by_key.remove(&oldest);
+            }
+        }
+        entries.order.push_back(key.clone());
+        entries.by_key.insert(key, value.clone());
+        Ok(value)
+    }
+}
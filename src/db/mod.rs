@@ -0,0 +1,45 @@
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool, PoolError, PooledConnection};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Outcome, State};
+use std::ops::Deref;
+use std::time::Duration;
+
+use crate::config::DatabaseConfig;
+
+/// The connection pool, kept in Rocket's managed state.
+pub type PgPool = Pool<ConnectionManager<PgConnection>>;
+
+/// Builds the connection pool. Fails if no connection can be opened within the configured
+/// timeout, so a bad database URL is reported at startup rather than on the first request.
+pub fn init_pool(config: &DatabaseConfig) -> Result<PgPool, PoolError> {
+    let manager = ConnectionManager::<PgConnection>::new(config.url.as_str());
+    Pool::builder()
+        .max_size(config.pool_size)
+        .connection_timeout(Duration::from_secs(config.timeout))
+        .build(manager)
+}
+
+/// Request guard handing a handler a connection checked out of the pool.
+pub struct DbConn(PooledConnection<ConnectionManager<PgConnection>>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for DbConn {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<DbConn, ()> {
+        let pool = request.guard::<State<PgPool>>()?;
+        match pool.get() {
+            Ok(conn) => Outcome::Success(DbConn(conn)),
+            Err(_) => Outcome::Failure((Status::ServiceUnavailable, ())),
+        }
+    }
+}
+
+impl Deref for DbConn {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &PgConnection {
+        &self.0
+    }
+}
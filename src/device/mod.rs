@@ -0,0 +1,451 @@
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use vulkano::instance::Features;
+use vulkano::instance::Instance;
+use vulkano::instance::InstanceCreationError;
+use vulkano::instance::InstanceExtensions;
+use vulkano::instance::Limits;
+use vulkano::instance::PhysicalDevice;
+use vulkano::instance::PhysicalDeviceType;
+use vulkano::instance::QueueFamily;
+use vulkano::instance::Version;
+
+/// What a physical device is and what it can do, as reported by the driver.
+#[derive(Serialize)]
+pub struct DeviceInfo {
+    /// Position of the device in `PhysicalDevice::enumerate`.
+    pub index: usize,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub api_version: String,
+    pub driver_version: u32,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    /// Names of the optional features the device supports.
+    pub features: Vec<&'static str>,
+    pub memory_heaps: Vec<MemoryHeapInfo>,
+    pub memory_types: Vec<MemoryTypeInfo>,
+    pub queue_families: Vec<QueueFamilyInfo>,
+    pub limits: LimitsInfo,
+}
+
+#[derive(Serialize)]
+pub struct MemoryHeapInfo {
+    pub id: u32,
+    pub size: usize,
+    pub device_local: bool,
+}
+
+#[derive(Serialize)]
+pub struct MemoryTypeInfo {
+    pub id: u32,
+    pub heap: u32,
+    pub device_local: bool,
+    pub host_visible: bool,
+    pub host_coherent: bool,
+    pub host_cached: bool,
+    pub lazily_allocated: bool,
+}
+
+#[derive(Serialize)]
+pub struct QueueFamilyInfo {
+    pub id: u32,
+    pub queue_count: usize,
+    pub graphics: bool,
+    pub compute: bool,
+    pub transfer: bool,
+    pub sparse_binding: bool,
+}
+
+/// The subset of the device limits that matters for the requests this server accepts.
+#[derive(Serialize)]
+pub struct LimitsInfo {
+    pub max_image_dimension_2d: u32,
+    pub max_image_array_layers: u32,
+    pub max_framebuffer_width: u32,
+    pub max_framebuffer_height: u32,
+    pub framebuffer_color_sample_counts: u32,
+    pub framebuffer_depth_sample_counts: u32,
+    pub max_viewports: u32,
+    pub max_vertex_input_attributes: u32,
+    pub max_bound_descriptor_sets: u32,
+    pub max_push_constants_size: u32,
+    pub max_uniform_buffer_range: u32,
+    pub max_storage_buffer_range: u32,
+    pub max_sampler_anisotropy: f32,
+    pub max_compute_shared_memory_size: u32,
+    pub max_compute_work_group_count: [u32; 3],
+    pub max_compute_work_group_invocations: u32,
+    pub max_compute_work_group_size: [u32; 3],
+    pub timestamp_period: f32,
+}
+
+/// Creates an instance without any extensions, which is all that offscreen work needs.
+pub fn instance() -> Result<Arc<Instance>, InstanceCreationError> {
+    Instance::new(None, &InstanceExtensions::none(), None)
+}
+
+/// How a request picks its physical device.
+///
+/// Written as a string both in the config file and in request bodies:
+///
+/// - `auto`: the best device by type, discrete > integrated > virtual > CPU;
+/// - a number: the device at that position in `GET /devices`;
+/// - `discrete`, `integrated`, `virtual` or `cpu`: a device of that type if there is one,
+///   otherwise the best remaining device as for `auto`;
+/// - `name:<text>`: the first device whose name contains `<text>`, ignoring case.
+#[derive(Clone, Debug)]
+pub enum DeviceSelector {
+    Auto,
+    Index(usize),
+    Prefer(PhysicalDeviceType),
+    Name(String),
+}
+
+impl Default for DeviceSelector {
+    fn default() -> DeviceSelector {
+        DeviceSelector::Auto
+    }
+}
+
+impl FromStr for DeviceSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DeviceSelector, String> {
+        if s.starts_with("name:") {
+            return Ok(DeviceSelector::Name(s["name:".len()..].to_lowercase()));
+        }
+        if let Ok(index) = s.parse() {
+            return Ok(DeviceSelector::Index(index));
+        }
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(DeviceSelector::Auto),
+            "discrete" => Ok(DeviceSelector::Prefer(PhysicalDeviceType::DiscreteGpu)),
+            "integrated" => Ok(DeviceSelector::Prefer(PhysicalDeviceType::IntegratedGpu)),
+            "virtual" => Ok(DeviceSelector::Prefer(PhysicalDeviceType::VirtualGpu)),
+            "cpu" => Ok(DeviceSelector::Prefer(PhysicalDeviceType::Cpu)),
+            _ => Err(format!(
+                "unknown device selector `{}`, expected auto, an index, discrete, integrated, \
+                 virtual, cpu or name:<text>",
+                s
+            )),
+        }
+    }
+}
+
+/// Writes the selector back in the form `from_str` reads.
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceSelector::Auto => write!(f, "auto"),
+            DeviceSelector::Index(index) => write!(f, "{}", index),
+            DeviceSelector::Prefer(ty) => {
+                let name = match ty {
+                    PhysicalDeviceType::DiscreteGpu => "discrete",
+                    PhysicalDeviceType::IntegratedGpu => "integrated",
+                    PhysicalDeviceType::VirtualGpu => "virtual",
+                    PhysicalDeviceType::Cpu => "cpu",
+                    PhysicalDeviceType::Other => "other",
+                };
+                write!(f, "{}", name)
+            }
+            DeviceSelector::Name(name) => write!(f, "name:{}", name),
+        }
+    }
+}
+
+impl Serialize for DeviceSelector {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceSelector {
+    fn deserialize<D>(deserializer: D) -> Result<DeviceSelector, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// The kind of queue the work needs.
+#[derive(Clone, Copy, Debug)]
+pub enum QueueKind {
+    Graphics,
+    Compute,
+}
+
+impl QueueKind {
+    fn supported_by(self, family: &QueueFamily) -> bool {
+        match self {
+            QueueKind::Graphics => family.supports_graphics(),
+            QueueKind::Compute => family.supports_compute(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SelectionError {
+    NoDevices,
+    IndexOutOfRange { index: usize, count: usize },
+    NoNameMatch(String),
+    /// No device matching the selector has a queue family of the needed kind.
+    NoQueueFamily { selector: DeviceSelector, kind: QueueKind },
+    /// The selected device could not be opened.
+    DeviceCreation { device: String, reason: String },
+}
+
+impl fmt::Display for SelectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SelectionError::NoDevices => write!(f, "no Vulkan device available"),
+            SelectionError::IndexOutOfRange { index, count } => write!(
+                f,
+                "no device at index {}, there are only {} devices",
+                index, count
+            ),
+            SelectionError::NoNameMatch(name) => write!(f, "no device name contains `{}`", name),
+            SelectionError::NoQueueFamily { selector, kind } => write!(
+                f,
+                "no device selected by {:?} has a {:?} queue family",
+                selector, kind
+            ),
+            SelectionError::DeviceCreation { device, reason } => {
+                write!(f, "failed to open device {}: {}", device, reason)
+            }
+        }
+    }
+}
+
+/// Orders device types for `DeviceSelector::Auto`, lower is better.
+fn type_rank(ty: PhysicalDeviceType) -> u8 {
+    match ty {
+        PhysicalDeviceType::DiscreteGpu => 0,
+        PhysicalDeviceType::IntegratedGpu => 1,
+        PhysicalDeviceType::VirtualGpu => 2,
+        PhysicalDeviceType::Cpu => 3,
+        PhysicalDeviceType::Other => 4,
+    }
+}
+
+/// Picks a physical device and one of its queue families able to run `kind` work.
+pub fn select<'a>(
+    instance: &'a Arc<Instance>,
+    selector: &DeviceSelector,
+    kind: QueueKind,
+) -> Result<(PhysicalDevice<'a>, QueueFamily<'a>), SelectionError> {
+    let devices: Vec<PhysicalDevice> = PhysicalDevice::enumerate(instance).collect();
+    if devices.is_empty() {
+        return Err(SelectionError::NoDevices);
+    }
+
+    let mut candidates: Vec<PhysicalDevice> = match selector {
+        DeviceSelector::Index(index) => match devices.get(*index) {
+            Some(physical) => vec![*physical],
+            None => {
+                return Err(SelectionError::IndexOutOfRange {
+                    index: *index,
+                    count: devices.len(),
+                })
+            }
+        },
+        DeviceSelector::Name(name) => {
+            let matching: Vec<_> = devices
+                .iter()
+                .cloned()
+                .filter(|physical| physical.name().to_lowercase().contains(name.as_str()))
+                .collect();
+            if matching.is_empty() {
+                return Err(SelectionError::NoNameMatch(name.clone()));
+            }
+            matching
+        }
+        DeviceSelector::Auto | DeviceSelector::Prefer(_) => devices,
+    };
+
+    // The sort is stable, so devices of the same rank keep their enumeration order.
+    if let DeviceSelector::Prefer(preferred) = selector {
+        candidates.sort_by_key(|physical| (physical.ty() != *preferred, type_rank(physical.ty())));
+    } else {
+        candidates.sort_by_key(|physical| type_rank(physical.ty()));
+    }
+
+    candidates
+        .into_iter()
+        .filter_map(|physical| {
+            physical
+                .queue_families()
+                .find(|family| kind.supported_by(family))
+                .map(|family| (physical, family))
+        })
+        .next()
+        .ok_or_else(|| SelectionError::NoQueueFamily {
+            selector: selector.clone(),
+            kind,
+        })
+}
+
+/// Describes every physical device the Vulkan loader knows about.
+pub fn enumerate(instance: &Arc<Instance>) -> Vec<DeviceInfo> {
+    PhysicalDevice::enumerate(instance).map(describe).collect()
+}
+
+pub fn describe(physical: PhysicalDevice) -> DeviceInfo {
+    DeviceInfo {
+        index: physical.index(),
+        name: physical.name().to_string(),
+        ty: format!("{:?}", physical.ty()),
+        api_version: version_string(physical.api_version()),
+        driver_version: physical.driver_version(),
+        vendor_id: physical.pci_vendor_id(),
+        device_id: physical.pci_device_id(),
+        features: feature_names(physical.supported_features()),
+        memory_heaps: physical
+            .memory_heaps()
+            .map(|heap| MemoryHeapInfo {
+                id: heap.id(),
+                size: heap.size(),
+                device_local: heap.is_device_local(),
+            })
+            .collect(),
+        memory_types: physical
+            .memory_types()
+            .map(|ty| MemoryTypeInfo {
+                id: ty.id(),
+                heap: ty.heap().id(),
+                device_local: ty.is_device_local(),
+                host_visible: ty.is_host_visible(),
+                host_coherent: ty.is_host_coherent(),
+                host_cached: ty.is_host_cached(),
+                lazily_allocated: ty.is_lazily_allocated(),
+            })
+            .collect(),
+        queue_families: physical.queue_families().map(describe_queue_family).collect(),
+        limits: describe_limits(&physical.limits()),
+    }
+}
+
+fn describe_queue_family(family: QueueFamily) -> QueueFamilyInfo {
+    QueueFamilyInfo {
+        id: family.id(),
+        queue_count: family.queues_count(),
+        graphics: family.supports_graphics(),
+        compute: family.supports_compute(),
+        // Graphics and compute queues can always transfer, even when the driver doesn't set
+        // the transfer bit on them.
+        transfer: family.supports_transfers()
+            || family.supports_graphics()
+            || family.supports_compute(),
+        sparse_binding: family.supports_sparse_binding(),
+    }
+}
+
+fn describe_limits(limits: &Limits) -> LimitsInfo {
+    LimitsInfo {
+        max_image_dimension_2d: limits.max_image_dimension_2d(),
+        max_image_array_layers: limits.max_image_array_layers(),
+        max_framebuffer_width: limits.max_framebuffer_width(),
+        max_framebuffer_height: limits.max_framebuffer_height(),
+        framebuffer_color_sample_counts: limits.framebuffer_color_sample_counts(),
+        framebuffer_depth_sample_counts: limits.framebuffer_depth_sample_counts(),
+        max_viewports: limits.max_viewports(),
+        max_vertex_input_attributes: limits.max_vertex_input_attributes(),
+        max_bound_descriptor_sets: limits.max_bound_descriptor_sets(),
+        max_push_constants_size: limits.max_push_constants_size(),
+        max_uniform_buffer_range: limits.max_uniform_buffer_range(),
+        max_storage_buffer_range: limits.max_storage_buffer_range(),
+        max_sampler_anisotropy: limits.max_sampler_anisotropy(),
+        max_compute_shared_memory_size: limits.max_compute_shared_memory_size(),
+        max_compute_work_group_count: limits.max_compute_work_group_count(),
+        max_compute_work_group_invocations: limits.max_compute_work_group_invocations(),
+        max_compute_work_group_size: limits.max_compute_work_group_size(),
+        timestamp_period: limits.timestamp_period(),
+    }
+}
+
+fn version_string(version: Version) -> String {
+    format!("{}.{}.{}", version.major, version.minor, version.patch)
+}
+
+/// Lists the names of the features set in `features`.
+macro_rules! feature_names {
+    ($features:expr, $($name:ident),* $(,)*) => {{
+        let mut names = Vec::new();
+        $(
+            if $features.$name {
+                names.push(stringify!($name));
+            }
+        )*
+        names
+    }};
+}
+
+fn feature_names(features: &Features) -> Vec<&'static str> {
+    feature_names!(
+        features,
+        robust_buffer_access,
+        full_draw_index_uint32,
+        image_cube_array,
+        independent_blend,
+        geometry_shader,
+        tessellation_shader,
+        sample_rate_shading,
+        dual_src_blend,
+        logic_op,
+        multi_draw_indirect,
+        draw_indirect_first_instance,
+        depth_clamp,
+        depth_bias_clamp,
+        fill_mode_non_solid,
+        depth_bounds,
+        wide_lines,
+        large_points,
+        alpha_to_one,
+        multi_viewport,
+        sampler_anisotropy,
+        texture_compression_etc2,
+        texture_compression_astc_ldr,
+        texture_compression_bc,
+        occlusion_query_precise,
+        pipeline_statistics_query,
+        vertex_pipeline_stores_and_atomics,
+        fragment_stores_and_atomics,
+        shader_tessellation_and_geometry_point_size,
+        shader_image_gather_extended,
+        shader_storage_image_extended_formats,
+        shader_storage_image_multisample,
+        shader_storage_image_read_without_format,
+        shader_storage_image_write_without_format,
+        shader_uniform_buffer_array_dynamic_indexing,
+        shader_sampled_image_array_dynamic_indexing,
+        shader_storage_buffer_array_dynamic_indexing,
+        shader_storage_image_array_dynamic_indexing,
+        shader_clip_distance,
+        shader_cull_distance,
+        shader_float64,
+        shader_int64,
+        shader_int16,
+        shader_resource_residency,
+        shader_resource_min_lod,
+        sparse_binding,
+        sparse_residency_buffer,
+        sparse_residency_image2d,
+        sparse_residency_image3d,
+        sparse_residency2_samples,
+        sparse_residency4_samples,
+        sparse_residency8_samples,
+        sparse_residency16_samples,
+        sparse_residency_aliased,
+        variable_multisample_rate,
+        inherited_queries,
+    )
+}
@@ -0,0 +1,213 @@
+use diesel::r2d2::PoolError;
+use diesel::result::Error as DieselError;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, status, Responder};
+use rocket_contrib::{JsonValue, SerdeError};
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::Arc;
+use vulkano::instance::InstanceCreationError;
+
+use crate::compute::ComputeError;
+use crate::config::ConfigErrors;
+use crate::device::SelectionError;
+use crate::jobs::{Interruption, SubmitError};
+use crate::render::RenderError;
+use crate::shaders::Compilation;
+
+/// Everything that can go wrong, at startup or while handling a request.
+///
+/// As a response it is the status code matching the variant and
+/// `{ "error": { "kind", "message", "details" } }`, where `kind` is the name from `kind()` and
+/// `details` is `null` unless the variant carries more than a message.
+#[derive(Debug)]
+pub enum Error {
+    /// The configuration is invalid, with one problem per entry.
+    Config(Vec<String>),
+    /// A query failed.
+    Database(String),
+    /// The database or the job queue can't take the request right now.
+    Unavailable(String),
+    /// The GPU took longer than a request may wait for it.
+    Timeout(String),
+    /// Vulkan failed on a request that should have worked.
+    Vulkan(String),
+    /// No device fits the request's selector.
+    Device(SelectionError),
+    /// A shader didn't compile; the details carry its hash and diagnostics.
+    Shader(Arc<Compilation>),
+    /// The request parsed but doesn't make sense.
+    Validation(String),
+    /// The request couldn't be parsed at all.
+    BadRequest(String),
+    NotFound(String),
+    /// None of the formats the client accepts can be produced.
+    NotAcceptable(String),
+    /// The request conflicts with the current state of what it refers to.
+    Conflict(String),
+    Internal(String),
+}
+
+impl Error {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Config(_) => "config",
+            Error::Database(_) => "database",
+            Error::Unavailable(_) => "unavailable",
+            Error::Timeout(_) => "timeout",
+            Error::Vulkan(_) => "vulkan",
+            Error::Device(_) => "device",
+            Error::Shader(_) => "shader_compilation",
+            Error::Validation(_) => "validation",
+            Error::BadRequest(_) => "bad_request",
+            Error::NotFound(_) => "not_found",
+            Error::NotAcceptable(_) => "not_acceptable",
+            Error::Conflict(_) => "conflict",
+            Error::Internal(_) => "internal",
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            Error::Config(_) | Error::Database(_) | Error::Vulkan(_) | Error::Internal(_) => {
+                Status::InternalServerError
+            }
+            Error::Unavailable(_) => Status::ServiceUnavailable,
+            Error::Timeout(_) => Status::GatewayTimeout,
+            Error::Device(_) | Error::Shader(_) | Error::Validation(_) => {
+                Status::UnprocessableEntity
+            }
+            Error::BadRequest(_) => Status::BadRequest,
+            Error::NotFound(_) => Status::NotFound,
+            Error::NotAcceptable(_) => Status::NotAcceptable,
+            Error::Conflict(_) => Status::Conflict,
+        }
+    }
+
+    fn details(&self) -> Option<JsonValue> {
+        match self {
+            Error::Config(problems) => Some(json!({ "problems": problems })),
+            Error::Shader(compilation) => Some(json!({
+                "hash": compilation.hash,
+                "stage": compilation.stage,
+                "diagnostics": compilation.diagnostics,
+            })),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(problems) => {
+                write!(f, "invalid configuration")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+            Error::Device(err) => write!(f, "{}", err),
+            Error::Shader(_) => write!(f, "shader compilation failed"),
+            Error::Database(message)
+            | Error::Unavailable(message)
+            | Error::Timeout(message)
+            | Error::Vulkan(message)
+            | Error::Validation(message)
+            | Error::BadRequest(message)
+            | Error::NotFound(message)
+            | Error::NotAcceptable(message)
+            | Error::Conflict(message)
+            | Error::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl<'r> Responder<'r> for Error {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let body = json!({
+            "error": {
+                "kind": self.kind(),
+                "message": self.to_string(),
+                "details": self.details().map(JsonValue::into_inner),
+            }
+        });
+        status::Custom(self.status(), body).respond_to(request)
+    }
+}
+
+impl From<ConfigErrors> for Error {
+    fn from(errors: ConfigErrors) -> Error {
+        Error::Config(errors.0)
+    }
+}
+
+impl From<DieselError> for Error {
+    fn from(err: DieselError) -> Error {
+        Error::Database(err.to_string())
+    }
+}
+
+impl From<PoolError> for Error {
+    fn from(err: PoolError) -> Error {
+        Error::Unavailable(format!("no database connection available: {}", err))
+    }
+}
+
+impl From<InstanceCreationError> for Error {
+    fn from(err: InstanceCreationError) -> Error {
+        Error::Vulkan(format!("failed to create Vulkan instance: {}", err))
+    }
+}
+
+impl From<SelectionError> for Error {
+    fn from(err: SelectionError) -> Error {
+        Error::Device(err)
+    }
+}
+
+impl From<SerdeError> for Error {
+    fn from(err: SerdeError) -> Error {
+        Error::Validation(err.to_string())
+    }
+}
+
+impl From<ComputeError> for Error {
+    fn from(err: ComputeError) -> Error {
+        match err {
+            ComputeError::Invalid(message) => Error::Validation(message),
+            ComputeError::Selection(err) => Error::Device(err),
+            ComputeError::Vulkan(message) => Error::Vulkan(message),
+            ComputeError::Interrupted(interruption) => Error::from(interruption),
+        }
+    }
+}
+
+impl From<RenderError> for Error {
+    fn from(err: RenderError) -> Error {
+        match err {
+            RenderError::Selection(err) => Error::Device(err),
+            RenderError::Vulkan(message) => Error::Vulkan(message),
+            err @ RenderError::Encoding(_) => Error::Internal(err.to_string()),
+            RenderError::Interrupted(interruption) => Error::from(interruption),
+        }
+    }
+}
+
+impl From<Interruption> for Error {
+    fn from(interruption: Interruption) -> Error {
+        match interruption {
+            Interruption::TimedOut => Error::Timeout(interruption.to_string()),
+            Interruption::Cancelled => Error::Unavailable(interruption.to_string()),
+        }
+    }
+}
+
+impl From<SubmitError> for Error {
+    fn from(err: SubmitError) -> Error {
+        Error::Unavailable(err.to_string())
+    }
+}
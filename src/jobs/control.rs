@@ -0,0 +1,92 @@
+use std::cmp;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use vulkano::sync::{FenceSignalFuture, FlushError, GpuFuture};
+
+/// How long waits block before checking again whether the job should stop.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Why a job was stopped before it finished.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interruption {
+    Cancelled,
+    TimedOut,
+}
+
+impl fmt::Display for Interruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Interruption::Cancelled => write!(f, "the job was cancelled"),
+            Interruption::TimedOut => write!(f, "the job exceeded its maximum runtime"),
+        }
+    }
+}
+
+/// Shared between a running job and the queue, so the job can be stopped either by
+/// `DELETE /jobs/<id>` or by running past its deadline.
+pub struct JobControl {
+    cancelled: AtomicBool,
+    deadline: Instant,
+}
+
+impl JobControl {
+    pub fn new(timeout: Duration) -> JobControl {
+        JobControl {
+            cancelled: AtomicBool::new(false),
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Why the job should stop, if it should.
+    pub fn interruption(&self) -> Option<Interruption> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            Some(Interruption::Cancelled)
+        } else if Instant::now() >= self.deadline {
+            Some(Interruption::TimedOut)
+        } else {
+            None
+        }
+    }
+
+    /// Fails if the job should stop. Called between the steps of a job that don't wait for the
+    /// GPU, such as uploading, recording and encoding, so that it ends soon after being stopped.
+    pub fn check(&self) -> Result<(), Interruption> {
+        match self.interruption() {
+            Some(interruption) => Err(interruption),
+            None => Ok(()),
+        }
+    }
+
+    /// Waits for the GPU to signal the fence of `future`, giving up as soon as the job is
+    /// interrupted. Other errors of the wait are passed on as the inner result.
+    ///
+    /// Giving up doesn't stop the GPU: dropping the future still blocks until the work is done.
+    pub fn wait<F>(
+        &self,
+        future: &FenceSignalFuture<F>,
+    ) -> Result<Result<(), FlushError>, Interruption>
+    where
+        F: GpuFuture,
+    {
+        loop {
+            if let Some(interruption) = self.interruption() {
+                return Err(interruption);
+            }
+            let now = Instant::now();
+            let remaining = if self.deadline > now {
+                self.deadline - now
+            } else {
+                Duration::from_millis(0)
+            };
+            match future.wait(Some(cmp::min(POLL_INTERVAL, remaining))) {
+                Err(FlushError::Timeout) => continue,
+                result => return Ok(result),
+            }
+        }
+    }
+}
@@ -0,0 +1,701 @@
+use diesel::prelude::*;
+use image::RgbaImage;
+use rocket::http::ContentType;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::compute::{self, ComputeRequest, OutputEncoding};
+use crate::config::JobsConfig;
+use crate::context::GpuContext;
+use crate::db::PgPool;
+use crate::device::{DeviceSelector, QueueKind};
+use crate::models::{self, JobChanges, NewJob};
+use crate::render::{self, AnimationFormat, AnimationRequest, OutputFormat, RenderRequest};
+use crate::schema::jobs;
+use crate::shaders::Compilation;
+
+mod control;
+
+pub use self::control::{Interruption, JobControl};
+use self::control::POLL_INTERVAL;
+
+/// Primary key of the job's row in the `jobs` table.
+pub type JobId = i64;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    Render,
+    Animation,
+    Compute,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    /// Stopped by `DELETE /jobs/<id>`.
+    Cancelled,
+    /// Stopped for running longer than `jobs.timeout`.
+    TimedOut,
+}
+
+impl JobKind {
+    /// The name stored in the database and used in JSON.
+    pub fn name(self) -> &'static str {
+        match self {
+            JobKind::Render => "render",
+            JobKind::Animation => "animation",
+            JobKind::Compute => "compute",
+        }
+    }
+}
+
+impl FromStr for JobKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<JobKind, String> {
+        match s {
+            "render" => Ok(JobKind::Render),
+            "animation" => Ok(JobKind::Animation),
+            "compute" => Ok(JobKind::Compute),
+            _ => Err(format!("unknown job kind `{}`", s)),
+        }
+    }
+}
+
+impl JobStatus {
+    /// The name stored in the database and used in JSON.
+    pub fn name(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+            JobStatus::TimedOut => "timed_out",
+        }
+    }
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<JobStatus, String> {
+        match s {
+            "queued" => Ok(JobStatus::Queued),
+            "running" => Ok(JobStatus::Running),
+            "succeeded" => Ok(JobStatus::Succeeded),
+            "failed" => Ok(JobStatus::Failed),
+            "cancelled" => Ok(JobStatus::Cancelled),
+            "timed_out" => Ok(JobStatus::TimedOut),
+            _ => Err(format!("unknown job status `{}`", s)),
+        }
+    }
+}
+
+/// Work for the queue. Requests are validated and shaders compiled before it is built, so that
+/// mistakes the client can fix are answered right away instead of failing the job later.
+pub enum Work {
+    Render {
+        request: RenderRequest,
+        /// The decoded `request.textures`.
+        textures: Vec<RgbaImage>,
+        dimensions: [u32; 2],
+        device: DeviceSelector,
+        format: OutputFormat,
+    },
+    Animation {
+        request: AnimationRequest,
+        /// The decoded `request.scene.textures`.
+        textures: Vec<RgbaImage>,
+        dimensions: [u32; 2],
+        device: DeviceSelector,
+        format: AnimationFormat,
+    },
+    Compute {
+        request: ComputeRequest,
+        shader: Arc<Compilation>,
+        /// Used when the request doesn't pick a device itself.
+        default_device: DeviceSelector,
+        /// `compute.max_bytes` when the job was submitted.
+        max_bytes: u64,
+    },
+}
+
+impl Work {
+    fn kind(&self) -> JobKind {
+        match self {
+            Work::Render { .. } => JobKind::Render,
+            Work::Animation { .. } => JobKind::Animation,
+            Work::Compute { .. } => JobKind::Compute,
+        }
+    }
+
+    /// The request as it is stored with the job.
+    fn payload(&self) -> Value {
+        let payload = match self {
+            Work::Render { request, .. } => serde_json::to_value(request),
+            Work::Animation { request, .. } => serde_json::to_value(request),
+            Work::Compute { request, .. } => serde_json::to_value(request),
+        };
+        payload.expect("failed to serialize job request")
+    }
+
+    /// Name of the device the work will run on, or `None` if no device fits, in which case
+    /// running it fails with the reason.
+    fn device_name(&self, context: &GpuContext) -> Option<String> {
+        let (selector, kind) = match self {
+            Work::Render { device, .. } | Work::Animation { device, .. } => {
+                (device, QueueKind::Graphics)
+            }
+            Work::Compute {
+                request,
+                default_device,
+                ..
+            } => (
+                request.device.as_ref().unwrap_or(default_device),
+                QueueKind::Compute,
+            ),
+        };
+        context
+            .device(selector, kind)
+            .ok()
+            .map(|(gpu, _)| gpu.device.physical_device().name().to_string())
+    }
+
+    fn run(self, context: &GpuContext, control: &JobControl) -> Result<JobOutput, String> {
+        match self {
+            Work::Render {
+                request,
+                textures,
+                dimensions,
+                device,
+                format,
+            } => {
+                let image =
+                    render::render(context, &request, &textures, dimensions, &device, control)
+                        .map_err(|err| err.to_string())?;
+                control.check().map_err(|interruption| interruption.to_string())?;
+                Ok(JobOutput {
+                    content_type: format.content_type(),
+                    headers: format.headers(&image),
+                    body: format.encode(&image).map_err(|err| err.to_string())?,
+                })
+            }
+            Work::Animation {
+                request,
+                textures,
+                dimensions,
+                device,
+                format,
+            } => {
+                let body = render::animate(
+                    context,
+                    &request,
+                    &textures,
+                    dimensions,
+                    &device,
+                    format,
+                    control,
+                )
+                .map_err(|err| err.to_string())?;
+                Ok(JobOutput {
+                    content_type: format.content_type(),
+                    headers: Vec::new(),
+                    body,
+                })
+            }
+            Work::Compute {
+                request,
+                shader,
+                default_device,
+                max_bytes,
+            } => {
+                let mut output = compute::run(
+                    context,
+                    &request,
+                    &shader,
+                    &default_device,
+                    max_bytes,
+                    control,
+                )
+                .map_err(|err| err.to_string())?;
+                match request.output {
+                    OutputEncoding::Binary => {
+                        let buffer = output.buffers.remove(0);
+                        Ok(JobOutput {
+                            content_type: ContentType::Binary,
+                            headers: Vec::new(),
+                            body: compute::words_to_bytes(&buffer.words),
+                        })
+                    }
+                    encoding => Ok(JobOutput {
+                        content_type: ContentType::JSON,
+                        headers: Vec::new(),
+                        body: serde_json::to_vec(&output.into_result(encoding))
+                            .expect("failed to serialize compute result"),
+                    }),
+                }
+            }
+        }
+    }
+}
+
+/// The encoded result of a job, ready to be sent as is.
+pub struct JobOutput {
+    pub content_type: ContentType,
+    /// Sent along with the body, e.g. the dimensions of raw pixels.
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+/// A job as kept in memory, mirroring its row plus the output once there is one.
+struct Job {
+    kind: JobKind,
+    status: JobStatus,
+    device: Option<String>,
+    submitted: SystemTime,
+    started: Option<SystemTime>,
+    finished: Option<SystemTime>,
+    error: Option<String>,
+    output: Option<Arc<JobOutput>>,
+    /// Set while the job runs.
+    control: Option<Arc<JobControl>>,
+}
+
+/// What `GET /jobs/<id>` reports about a job.
+#[derive(Serialize)]
+pub struct JobInfo {
+    pub id: JobId,
+    pub kind: JobKind,
+    pub status: JobStatus,
+    /// Name of the device the job ran on.
+    pub device: Option<String>,
+    pub timing: Timing,
+    pub error: Option<String>,
+    /// Where the output can be downloaded, once the job has succeeded. Outputs are only kept
+    /// in memory, so jobs from before a restart have none.
+    pub result: Option<String>,
+}
+
+/// Timestamps in RFC 3339 and durations in milliseconds.
+#[derive(Serialize)]
+pub struct Timing {
+    pub submitted_at: String,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    /// Time spent waiting for a worker.
+    pub queued_ms: Option<u64>,
+    /// Time spent running.
+    pub running_ms: Option<u64>,
+}
+
+impl Job {
+    /// The job as its row records it. Rows with a kind or status this server doesn't know are
+    /// refused.
+    fn from_record(record: &models::Job) -> Result<Job, String> {
+        let invalid = |err| format!("job {}: {}", record.id, err);
+        Ok(Job {
+            kind: record.kind.parse().map_err(invalid)?,
+            status: record.status.parse().map_err(invalid)?,
+            device: record.device.clone(),
+            submitted: record.created_at,
+            started: record.started_at,
+            finished: record.finished_at,
+            error: record.error.clone(),
+            output: None,
+            control: None,
+        })
+    }
+
+    /// The columns that change while the job is processed.
+    fn changes(&self) -> JobChanges {
+        JobChanges {
+            status: self.status.name().to_string(),
+            device: self.device.clone(),
+            started_at: self.started,
+            finished_at: self.finished,
+            error: self.error.clone(),
+            updated_at: SystemTime::now(),
+        }
+    }
+
+    fn info(&self, id: JobId) -> JobInfo {
+        JobInfo {
+            id,
+            kind: self.kind,
+            status: self.status,
+            device: self.device.clone(),
+            timing: Timing {
+                submitted_at: rfc3339(self.submitted),
+                started_at: self.started.map(rfc3339),
+                finished_at: self.finished.map(rfc3339),
+                queued_ms: self.started.map(|started| millis_between(self.submitted, started)),
+                running_ms: self
+                    .started
+                    .and_then(|started| self.finished.map(|end| millis_between(started, end))),
+            },
+            error: self.error.clone(),
+            result: self.output.as_ref().map(|_| format!("/jobs/{}/result", id)),
+        }
+    }
+}
+
+fn rfc3339(at: SystemTime) -> String {
+    let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let spec = time::Timespec::new(
+        since_epoch.as_secs() as i64,
+        since_epoch.subsec_nanos() as i32,
+    );
+    time::at_utc(spec).rfc3339().to_string()
+}
+
+fn millis_between(start: SystemTime, end: SystemTime) -> u64 {
+    let elapsed = end.duration_since(start).unwrap_or_default();
+    elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
+}
+
+/// Why a submission was turned away.
+#[derive(Debug)]
+pub enum SubmitError {
+    /// As many jobs as `jobs.queue_size` are already waiting.
+    QueueFull(usize),
+    /// The job's row could not be created.
+    Database(String),
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubmitError::QueueFull(waiting) => write!(
+                f,
+                "the job queue is full ({} jobs waiting), try again later",
+                waiting
+            ),
+            SubmitError::Database(message) => write!(f, "failed to record the job: {}", message),
+        }
+    }
+}
+
+/// Jobs waiting for, being run by or finished by a fixed pool of worker threads.
+///
+/// Every job has a row in the `jobs` table that is updated as it progresses, so its history
+/// survives restarts. Pending work and outputs only live in memory: finished jobs keep their
+/// output until `retained` newer ones have finished.
+pub struct JobQueue {
+    pool: PgPool,
+    state: Mutex<QueueState>,
+    /// Signalled whenever work is queued.
+    available: Condvar,
+    queue_size: usize,
+    retained: usize,
+    /// Longest a job may run.
+    timeout: Duration,
+}
+
+struct QueueState {
+    jobs: HashMap<JobId, Job>,
+    pending: VecDeque<(JobId, Work)>,
+    /// Places in `pending` taken by submissions whose row is still being inserted.
+    reserved: usize,
+    /// Finished jobs, oldest first.
+    finished: VecDeque<JobId>,
+}
+
+impl JobQueue {
+    /// Creates the queue and starts its workers, which run for the lifetime of the server.
+    ///
+    /// Jobs left queued or running by a previous run of the server are marked as failed first,
+    /// since their work was lost with it.
+    pub fn start(context: Arc<GpuContext>, pool: PgPool, config: &JobsConfig) -> Arc<JobQueue> {
+        if let Err(err) = interrupt_unfinished(&pool) {
+            eprintln!("warning: failed to mark interrupted jobs: {}", err);
+        }
+
+        let queue = Arc::new(JobQueue {
+            pool,
+            state: Mutex::new(QueueState {
+                jobs: HashMap::new(),
+                pending: VecDeque::new(),
+                reserved: 0,
+                finished: VecDeque::new(),
+            }),
+            available: Condvar::new(),
+            queue_size: config.queue_size,
+            retained: config.retained,
+            timeout: Duration::from_secs(config.timeout),
+        });
+
+        for index in 0..config.workers {
+            let queue = queue.clone();
+            let context = context.clone();
+            thread::Builder::new()
+                .name(format!("job-worker-{}", index))
+                .spawn(move || queue.work(&context))
+                .expect("failed to spawn job worker");
+        }
+
+        queue
+    }
+
+    pub fn submit(&self, work: Work) -> Result<JobInfo, SubmitError> {
+        // The lock isn't held while talking to the database, so the job's place is reserved
+        // first and given back if its row can't be created.
+        {
+            let mut state = self.state.lock().unwrap();
+            let waiting = state.pending.len() + state.reserved;
+            if waiting >= self.queue_size {
+                return Err(SubmitError::QueueFull(waiting));
+            }
+            state.reserved += 1;
+        }
+
+        let record = self.insert(&work);
+        let mut state = self.state.lock().unwrap();
+        state.reserved -= 1;
+        let record = record?;
+
+        let job = Job::from_record(&record).map_err(SubmitError::Database)?;
+        let info = job.info(record.id);
+        state.jobs.insert(record.id, job);
+        state.pending.push_back((record.id, work));
+        self.available.notify_one();
+        Ok(info)
+    }
+
+    /// Creates the row of a new job.
+    fn insert(&self, work: &Work) -> Result<models::Job, SubmitError> {
+        let now = SystemTime::now();
+        let new_job = NewJob {
+            kind: work.kind().name().to_string(),
+            request: work.payload(),
+            created_at: now,
+            updated_at: now,
+        };
+        let conn = self
+            .pool
+            .get()
+            .map_err(|err| SubmitError::Database(err.to_string()))?;
+        diesel::insert_into(jobs::table)
+            .values(&new_job)
+            .get_result::<models::Job>(&*conn)
+            .map_err(|err| SubmitError::Database(err.to_string()))
+    }
+
+    /// Describes a job from its row, or from memory when it is still there and so possibly
+    /// more recent and with an output. Fails for rows `Job::from_record` refuses.
+    pub fn describe(&self, record: &models::Job) -> Result<JobInfo, String> {
+        let state = self.state.lock().unwrap();
+        match state.jobs.get(&record.id) {
+            Some(job) => Ok(job.info(record.id)),
+            None => Job::from_record(record).map(|job| job.info(record.id)),
+        }
+    }
+
+    /// Returns the output of a succeeded job, or the status of one that hasn't succeeded.
+    /// `None` means the job isn't in memory, either because it doesn't exist or because its
+    /// output was dropped.
+    pub fn output(&self, id: JobId) -> Option<Result<Arc<JobOutput>, JobStatus>> {
+        let state = self.state.lock().unwrap();
+        state
+            .jobs
+            .get(&id)
+            .map(|job| job.output.clone().ok_or(job.status))
+    }
+
+    /// Cancels a queued or running job, returning the status it had. Queued jobs are cancelled
+    /// right away; running ones are stopped by their worker within `POLL_INTERVAL`.
+    ///
+    /// Returns `None` for jobs that are neither queued nor running, including the ones no longer
+    /// kept in memory.
+    pub fn cancel(&self, id: JobId) -> Option<JobStatus> {
+        let status = {
+            let mut state = self.state.lock().unwrap();
+            let status = state.jobs.get(&id)?.status;
+            match status {
+                JobStatus::Queued => {
+                    state.pending.retain(|&(pending, _)| pending != id);
+                    // Marked right away, so that a concurrent cancel finds nothing left to do
+                    // and the job is only finished once.
+                    if let Some(job) = state.jobs.get_mut(&id) {
+                        job.status = JobStatus::Cancelled;
+                    }
+                }
+                JobStatus::Running => {
+                    if let Some(ref control) = state.jobs[&id].control {
+                        control.cancel();
+                    }
+                }
+                _ => return None,
+            }
+            status
+        };
+
+        if status == JobStatus::Queued {
+            let interruption = Interruption::Cancelled;
+            self.finish(id, Err(interruption.to_string()), Some(interruption));
+        }
+        Some(status)
+    }
+
+    fn work(&self, context: &Arc<GpuContext>) {
+        loop {
+            let (id, work, control) = self.next();
+            let device = work.device_name(context);
+            self.update(id, |job| job.device = device);
+
+            // Each job runs on its own thread so that a panicking driver only fails the job
+            // instead of taking the worker down with it, and so that an interrupted job can be
+            // reported as such right away.
+            let (sender, receiver) = mpsc::channel();
+            let context = context.clone();
+            let job_control = control.clone();
+            thread::spawn(move || {
+                // Nobody is listening anymore if the job was reported as interrupted already.
+                let _ = sender.send(work.run(&context, &job_control));
+            });
+
+            let mut running = true;
+            let outcome = loop {
+                match receiver.recv_timeout(POLL_INTERVAL) {
+                    Ok(outcome) => {
+                        running = false;
+                        break outcome;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Some(interruption) = control.interruption() {
+                            break Err(interruption.to_string());
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        running = false;
+                        break Err("job thread panicked".to_string());
+                    }
+                }
+            };
+            let interruption = outcome.as_ref().err().and_then(|_| control.interruption());
+            self.finish(id, outcome, interruption);
+
+            // Jobs check for interruptions between their steps and stop at the next one. The
+            // worker waits for that before taking another job, so that no more than
+            // `jobs.workers` jobs ever hold on to a device.
+            if running {
+                let _ = receiver.recv();
+            }
+        }
+    }
+
+    /// Blocks until work is queued and marks it as running.
+    fn next(&self) -> (JobId, Work, Arc<JobControl>) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some((id, work)) = state.pending.pop_front() {
+                let control = Arc::new(JobControl::new(self.timeout));
+                if let Some(job) = state.jobs.get_mut(&id) {
+                    job.status = JobStatus::Running;
+                    job.started = Some(SystemTime::now());
+                    job.control = Some(control.clone());
+                }
+                return (id, work, control);
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    /// Records how a job ended. Failures caused by `interruption` get its status instead of
+    /// `Failed`.
+    fn finish(
+        &self,
+        id: JobId,
+        outcome: Result<JobOutput, String>,
+        interruption: Option<Interruption>,
+    ) {
+        self.update(id, |job| {
+            job.finished = Some(SystemTime::now());
+            job.control = None;
+            match outcome {
+                Ok(output) => {
+                    job.status = JobStatus::Succeeded;
+                    job.output = Some(Arc::new(output));
+                }
+                Err(err) => {
+                    job.status = match interruption {
+                        Some(Interruption::Cancelled) => JobStatus::Cancelled,
+                        Some(Interruption::TimedOut) => JobStatus::TimedOut,
+                        None => JobStatus::Failed,
+                    };
+                    job.error = Some(err);
+                }
+            }
+        });
+
+        let mut state = self.state.lock().unwrap();
+        state.finished.push_back(id);
+        while state.finished.len() > self.retained {
+            if let Some(oldest) = state.finished.pop_front() {
+                state.jobs.remove(&oldest);
+            }
+        }
+    }
+
+    /// Applies `change` to the job in memory, then writes the job's new state to its row.
+    ///
+    /// A failed write is only logged: the job carries on and memory stays authoritative for as
+    /// long as the job is kept there.
+    fn update<F>(&self, id: JobId, change: F)
+    where
+        F: FnOnce(&mut Job),
+    {
+        let changes = {
+            let mut state = self.state.lock().unwrap();
+            match state.jobs.get_mut(&id) {
+                Some(job) => {
+                    change(job);
+                    job.changes()
+                }
+                None => return,
+            }
+        };
+
+        let written = self.pool.get().map_err(|err| err.to_string()).and_then(|conn| {
+            diesel::update(jobs::table.find(id))
+                .set(&changes)
+                .execute(&*conn)
+                .map_err(|err| err.to_string())
+        });
+        if let Err(err) = written {
+            eprintln!("warning: failed to record job {}: {}", id, err);
+        }
+    }
+}
+
+fn interrupt_unfinished(pool: &PgPool) -> Result<usize, String> {
+    let conn = pool.get().map_err(|err| err.to_string())?;
+    let unfinished = vec![JobStatus::Queued.name(), JobStatus::Running.name()];
+    let now = SystemTime::now();
+    diesel::update(jobs::table.filter(jobs::status.eq_any(unfinished)))
+        .set((
+            jobs::status.eq(JobStatus::Failed.name()),
+            jobs::error.eq("interrupted by a server restart"),
+            jobs::finished_at.eq(now),
+            jobs::updated_at.eq(now),
+        ))
+        .execute(&*conn)
+        .map_err(|err| err.to_string())
+}
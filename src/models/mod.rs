@@ -1,7 +1,73 @@
-#[derive(Queryable)]
+use serde_json::Value;
+use std::time::SystemTime;
+
+use crate::schema::{jobs, posts};
+
+#[derive(Queryable, Serialize)]
 pub struct Post {
   pub id: i32,
   pub title: String,
   pub body: String,
   pub published: bool,
 }
+
+#[derive(Insertable, Deserialize)]
+#[table_name = "posts"]
+pub struct NewPost {
+  pub title: String,
+  pub body: String,
+  #[serde(default)]
+  pub published: bool,
+}
+
+/// A partial update of a post; fields left out of the request are left untouched.
+#[derive(AsChangeset, Deserialize)]
+#[table_name = "posts"]
+pub struct PostChanges {
+  pub title: Option<String>,
+  pub body: Option<String>,
+  pub published: Option<bool>,
+}
+
+/// The record of a render or compute job. `kind` and `status` hold the names used by
+/// `jobs::JobKind` and `jobs::JobStatus`.
+#[derive(Queryable)]
+pub struct Job {
+  pub id: i64,
+  pub kind: String,
+  /// The request as submitted.
+  pub request: Value,
+  pub status: String,
+  /// Name of the device the job ran on.
+  pub device: Option<String>,
+  pub started_at: Option<SystemTime>,
+  pub finished_at: Option<SystemTime>,
+  pub error: Option<String>,
+  pub created_at: SystemTime,
+  pub updated_at: SystemTime,
+}
+
+/// The timestamps are written from Rust, as UTC like `started_at` and `finished_at`; the
+/// columns' `NOW()` defaults would be in the database server's time zone.
+#[derive(Insertable)]
+#[table_name = "jobs"]
+pub struct NewJob {
+  pub kind: String,
+  pub request: Value,
+  pub created_at: SystemTime,
+  pub updated_at: SystemTime,
+}
+
+/// What changes while a job runs; `None` fields are left untouched.
+#[derive(AsChangeset)]
+#[table_name = "jobs"]
+pub struct JobChanges {
+  pub status: String,
+  pub device: Option<String>,
+  pub started_at: Option<SystemTime>,
+  pub finished_at: Option<SystemTime>,
+  pub error: Option<String>,
+  /// Set from Rust for the same reason as in `NewJob`; every write to a job goes through here
+  /// or sets it alongside.
+  pub updated_at: SystemTime,
+}
@@ -0,0 +1,294 @@
+use cgmath::{Matrix4, SquareMatrix};
+use gif::{Repeat, SetParameter};
+use image::RgbaImage;
+use rocket::http::ContentType;
+use std::io::{Cursor, Write};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use super::output::OutputFormat;
+use super::request::RenderRequest;
+use super::scene::Scene;
+use super::transform::{self, Transform};
+use super::{encoding, RenderError};
+use crate::context::GpuContext;
+use crate::device::DeviceSelector;
+use crate::jobs::JobControl;
+
+/// Most frames an animation may have.
+pub const MAX_FRAMES: u32 = 1000;
+
+/// Highest frame rate; GIF frame delays are counted in hundredths of a second.
+pub const MAX_FPS: f32 = 100.0;
+
+/// How hard the GIF encoder works on each frame's palette, from 1 (best) to 30 (fastest).
+const GIF_QUANTIZATION_SPEED: i32 = 10;
+
+/// A scene rendered `frames` times, moved by a transform interpolated between `keyframes`.
+#[derive(Deserialize, Serialize)]
+pub struct AnimationRequest {
+    #[serde(flatten)]
+    pub scene: RenderRequest,
+    pub frames: u32,
+    #[serde(default = "default_fps")]
+    pub fps: f32,
+    /// Applied to the whole scene, before the transforms of the meshes. Without keyframes every
+    /// frame is the same.
+    #[serde(default)]
+    pub keyframes: Vec<Keyframe>,
+}
+
+fn default_fps() -> f32 {
+    24.0
+}
+
+/// Where the scene is at frame `frame`, counting from 0.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Keyframe {
+    pub frame: u32,
+    pub transform: Transform,
+}
+
+impl AnimationRequest {
+    /// Checks the scene like a single render, and the frames and keyframes on top.
+    pub fn validate(&self) -> Result<(), String> {
+        self.scene.validate()?;
+        if self.frames == 0 || self.frames > MAX_FRAMES {
+            return Err(format!("`frames` must be between 1 and {}", MAX_FRAMES));
+        }
+        if !(self.fps > 0.0 && self.fps <= MAX_FPS) {
+            return Err(format!("`fps` must be greater than 0 and at most {}", MAX_FPS));
+        }
+        for (i, keyframe) in self.keyframes.iter().enumerate() {
+            keyframe
+                .transform
+                .validate()
+                .map_err(|e| format!("keyframe {}: {}", i, e))?;
+            if keyframe.frame >= self.frames {
+                return Err(format!(
+                    "keyframe {}: frame {} is out of range for {} frames",
+                    i, keyframe.frame, self.frames
+                ));
+            }
+            if i > 0 && keyframe.frame <= self.keyframes[i - 1].frame {
+                return Err("keyframes must be in ascending order of `frame`".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// The transform of the scene at `frame`. Frames before the first keyframe and after the
+    /// last one hold still.
+    pub fn transform(&self, frame: u32) -> Matrix4<f32> {
+        if self.keyframes.is_empty() {
+            return Matrix4::identity();
+        }
+        match self.keyframes.iter().position(|k| k.frame > frame) {
+            Some(0) => self.keyframes[0].transform.matrix(),
+            Some(next) => {
+                let (from, to) = (&self.keyframes[next - 1], &self.keyframes[next]);
+                let amount = (frame - from.frame) as f32 / (to.frame - from.frame) as f32;
+                from.transform.interpolate(&to.transform, amount)
+            }
+            None => self.keyframes[self.keyframes.len() - 1].transform.matrix(),
+        }
+    }
+
+    /// How long each frame is shown, in hundredths of a second.
+    fn delay(&self) -> u16 {
+        (100.0 / self.fps).round().min(f32::from(u16::max_value())) as u16
+    }
+}
+
+/// How the frames of an animation are put together.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AnimationFormat {
+    /// An animated GIF that loops forever. GIFs have a palette of 256 colors per frame and only
+    /// fully transparent or opaque pixels.
+    Gif,
+    /// A zip archive of PNGs named after their frame number, `0000.png` first.
+    Zip,
+}
+
+impl Default for AnimationFormat {
+    fn default() -> AnimationFormat {
+        AnimationFormat::Gif
+    }
+}
+
+impl AnimationFormat {
+    pub fn from_name(name: &str) -> Result<AnimationFormat, String> {
+        match name.to_lowercase().as_str() {
+            "gif" => Ok(AnimationFormat::Gif),
+            "zip" => Ok(AnimationFormat::Zip),
+            _ => Err(format!("unknown format `{}`, expected gif or zip", name)),
+        }
+    }
+
+    /// The format producing the media type `top/sub`, if any. Wildcards pick GIF.
+    pub fn from_media_type(top: &str, sub: &str) -> Option<AnimationFormat> {
+        match (top.to_lowercase().as_str(), sub.to_lowercase().as_str()) {
+            ("*", "*") | ("image", "*") | ("image", "gif") => Some(AnimationFormat::Gif),
+            ("application", "zip") => Some(AnimationFormat::Zip),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> ContentType {
+        match self {
+            AnimationFormat::Gif => ContentType::GIF,
+            AnimationFormat::Zip => ContentType::new("application", "zip"),
+        }
+    }
+
+    /// Checks that the format can hold frames of `dimensions`.
+    pub fn check_dimensions(self, dimensions: [u32; 2]) -> Result<(), String> {
+        let max = u32::from(u16::max_value());
+        if self == AnimationFormat::Gif && (dimensions[0] > max || dimensions[1] > max) {
+            return Err(format!("GIFs are limited to {}x{}", max, max));
+        }
+        Ok(())
+    }
+}
+
+/// Renders every frame of `request` into an offscreen image of `dimensions` and encodes them as
+/// they come, so only one frame is held uncompressed at a time. The request must have passed
+/// `AnimationRequest::validate`, and `check_limits` and `AnimationFormat::check_dimensions`.
+///
+/// The scene is uploaded once and drawn for each frame with its transform.
+pub fn animate(
+    context: &GpuContext,
+    request: &AnimationRequest,
+    textures: &[RgbaImage],
+    dimensions: [u32; 2],
+    selector: &DeviceSelector,
+    format: AnimationFormat,
+    control: &JobControl,
+) -> Result<Vec<u8>, RenderError> {
+    let scene = Scene::prepare(context, &request.scene, textures, selector, control)?;
+    let view_projection = transform::view_projection(
+        request.scene.camera.as_ref(),
+        request.scene.projection.as_ref(),
+        dimensions,
+    );
+    let clear_color = request.scene.clear_color();
+    let frames = (0..request.frames).map(|frame| {
+        scene.draw(
+            dimensions,
+            clear_color,
+            view_projection * request.transform(frame),
+            control,
+        )
+    });
+
+    match format {
+        AnimationFormat::Gif => encode_gif(frames, dimensions, request.delay()),
+        AnimationFormat::Zip => encode_zip(frames),
+    }
+}
+
+fn encode_gif<I>(frames: I, dimensions: [u32; 2], delay: u16) -> Result<Vec<u8>, RenderError>
+where
+    I: Iterator<Item = Result<RgbaImage, RenderError>>,
+{
+    let (width, height) = (dimensions[0] as u16, dimensions[1] as u16);
+    let mut encoded = Vec::new();
+    {
+        // The trailer is written when the encoder is dropped.
+        let mut encoder = gif::Encoder::new(&mut encoded, width, height, &[]).map_err(encoding)?;
+        encoder.set(Repeat::Infinite).map_err(encoding)?;
+        for image in frames {
+            let mut pixels = image?.into_raw();
+            let mut frame =
+                gif::Frame::from_rgba_speed(width, height, &mut pixels, GIF_QUANTIZATION_SPEED);
+            frame.delay = delay;
+            encoder.write_frame(&frame).map_err(encoding)?;
+        }
+    }
+    Ok(encoded)
+}
+
+fn encode_zip<I>(frames: I) -> Result<Vec<u8>, RenderError>
+where
+    I: Iterator<Item = Result<RgbaImage, RenderError>>,
+{
+    let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+    // PNGs are compressed already.
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    for (index, image) in frames.enumerate() {
+        let png = OutputFormat::Png.encode(&image?)?;
+        archive
+            .start_file(format!("{:04}.png", index), options)
+            .map_err(encoding)?;
+        archive.write_all(&png).map_err(encoding)?;
+    }
+    let encoded = archive.finish().map_err(encoding)?;
+    Ok(encoded.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(fps: f32, keyframes: &str) -> AnimationRequest {
+        let json = format!(
+            r#"{{"frames": 10, "fps": {}, "keyframes": {}}}"#,
+            fps, keyframes
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn at(translation: [f32; 3], rotation: [f32; 3]) -> Matrix4<f32> {
+        Transform {
+            translation,
+            rotation,
+            scale: [1.0, 1.0, 1.0],
+        }
+        .matrix()
+    }
+
+    fn assert_close(actual: Matrix4<f32>, expected: Matrix4<f32>) {
+        let (a, e): (&[f32; 16], &[f32; 16]) = (actual.as_ref(), expected.as_ref());
+        assert!(
+            a.iter().zip(e).all(|(a, e)| (a - e).abs() < 1e-5),
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    const KEYFRAMES: &str = r#"[
+        {"frame": 2, "transform": {"translation": [0, 0, 0]}},
+        {"frame": 6, "transform": {"translation": [4, 0, 0], "rotation": [0, 0, 90]}}
+    ]"#;
+
+    #[test]
+    fn no_keyframes_means_no_movement() {
+        let request = request(24.0, "[]");
+        assert_eq!(request.transform(0), Matrix4::identity());
+        assert_eq!(request.transform(9), Matrix4::identity());
+    }
+
+    #[test]
+    fn frames_outside_the_keyframes_hold_still() {
+        let request = request(24.0, KEYFRAMES);
+        assert_close(request.transform(0), at([0.0; 3], [0.0; 3]));
+        assert_close(request.transform(2), at([0.0; 3], [0.0; 3]));
+        assert_close(request.transform(6), at([4.0, 0.0, 0.0], [0.0, 0.0, 90.0]));
+        assert_close(request.transform(9), at([4.0, 0.0, 0.0], [0.0, 0.0, 90.0]));
+    }
+
+    #[test]
+    fn frames_between_keyframes_are_interpolated() {
+        let request = request(24.0, KEYFRAMES);
+        assert_close(request.transform(3), at([1.0, 0.0, 0.0], [0.0, 0.0, 22.5]));
+        assert_close(request.transform(4), at([2.0, 0.0, 0.0], [0.0, 0.0, 45.0]));
+    }
+
+    #[test]
+    fn delays_are_rounded_hundredths() {
+        assert_eq!(request(24.0, "[]").delay(), 4);
+        assert_eq!(request(100.0, "[]").delay(), 1);
+        assert_eq!(request(0.001, "[]").delay(), u16::max_value());
+    }
+}
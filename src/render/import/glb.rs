@@ -0,0 +1,218 @@
+use cgmath::{InnerSpace, Matrix, Matrix4, Point3, SquareMatrix, Transform, Vector3};
+use gltf::mesh::Mode;
+use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+use gltf::{buffer, image, Document, Gltf, Node, Primitive};
+
+use super::Imported;
+use crate::render::mesh::{Mesh, Position, Topology};
+use crate::render::texture::{AddressMode, FilterMode, SamplerSpec, TextureSpec};
+
+/// Imports the default scene of a glTF 2.0 binary file, or its first scene when there is no
+/// default. Node transforms are applied to the vertices, so the meshes come out in world space.
+pub fn import(data: &[u8]) -> Result<Imported, String> {
+    let Gltf { document, blob } =
+        Gltf::from_slice(data).map_err(|e| format!("could not parse glTF file: {}", e))?;
+    if let Some(extension) = document.extensions_required().next() {
+        return Err(format!(
+            "the glTF extension `{}` is not supported",
+            extension
+        ));
+    }
+    let blob = blob.ok_or_else(|| "only binary glTF (.glb) files are supported".to_string())?;
+    for buffer in document.buffers() {
+        if let buffer::Source::Uri(uri) = buffer.source() {
+            return Err(format!(
+                "buffer `{}` is external, only self-contained .glb files are supported",
+                uri
+            ));
+        }
+    }
+    check_ranges(&document, blob.len())?;
+
+    let mut textures = Vec::new();
+    for texture in document.textures() {
+        let bytes = match texture.source().source() {
+            image::Source::View { view, mime_type } => {
+                if mime_type != "image/png" && mime_type != "image/jpeg" {
+                    return Err(format!("images of type {} are not supported", mime_type));
+                }
+                &blob[view.offset()..view.offset() + view.length()]
+            }
+            image::Source::Uri { uri, .. } => {
+                return Err(format!(
+                    "image `{}` is external, only self-contained .glb files are supported",
+                    uri
+                ))
+            }
+        };
+        let sampler = texture.sampler();
+        textures.push(TextureSpec {
+            data: base64::encode(bytes),
+            sampler: SamplerSpec {
+                mag_filter: match sampler.mag_filter() {
+                    Some(MagFilter::Nearest) => FilterMode::Nearest,
+                    _ => FilterMode::Linear,
+                },
+                min_filter: match sampler.min_filter() {
+                    Some(MinFilter::Nearest)
+                    | Some(MinFilter::NearestMipmapNearest)
+                    | Some(MinFilter::NearestMipmapLinear) => FilterMode::Nearest,
+                    _ => FilterMode::Linear,
+                },
+                address_mode: match sampler.wrap_s() {
+                    WrappingMode::ClampToEdge => AddressMode::ClampToEdge,
+                    WrappingMode::MirroredRepeat => AddressMode::MirroredRepeat,
+                    WrappingMode::Repeat => AddressMode::Repeat,
+                },
+            },
+        });
+    }
+
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or_else(|| "glTF file has no scene".to_string())?;
+    let mut meshes = Vec::new();
+    for node in scene.nodes() {
+        import_node(&node, Matrix4::identity(), &blob, &mut meshes)?;
+    }
+    if meshes.is_empty() {
+        return Err("glTF scene has no meshes".to_string());
+    }
+
+    Ok(Imported { meshes, textures })
+}
+
+/// Checks that every buffer view lies within its buffer, every accessor within its view and
+/// every buffer within the binary chunk. The parser only checks that indices refer to something,
+/// and reading out of range would panic.
+fn check_ranges(document: &Document, blob_len: usize) -> Result<(), String> {
+    for buffer in document.buffers() {
+        if buffer.length() > blob_len {
+            return Err(format!(
+                "buffer {} is {} bytes long but the binary chunk only has {}",
+                buffer.index(),
+                buffer.length(),
+                blob_len
+            ));
+        }
+    }
+    for view in document.views() {
+        let end = view.offset().checked_add(view.length());
+        if end.map_or(true, |end| end > view.buffer().length()) {
+            return Err(format!(
+                "buffer view {} reaches past the end of buffer {}",
+                view.index(),
+                view.buffer().index()
+            ));
+        }
+    }
+    for accessor in document.accessors() {
+        let view = accessor.view();
+        let stride = view.stride().unwrap_or_else(|| accessor.size());
+        let end = match accessor.count() {
+            0 => Some(accessor.offset()),
+            count => (count - 1)
+                .checked_mul(stride)
+                .and_then(|bytes| bytes.checked_add(accessor.size()))
+                .and_then(|bytes| bytes.checked_add(accessor.offset())),
+        };
+        if end.map_or(true, |end| end > view.length()) {
+            return Err(format!(
+                "accessor {} reaches past the end of buffer view {}",
+                accessor.index(),
+                view.index()
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn import_node(
+    node: &Node,
+    parent: Matrix4<f32>,
+    blob: &[u8],
+    meshes: &mut Vec<Mesh>,
+) -> Result<(), String> {
+    let world = parent * Matrix4::from(node.transform().matrix());
+    if let Some(mesh) = node.mesh() {
+        for primitive in mesh.primitives() {
+            meshes.push(
+                import_primitive(&primitive, world, blob)
+                    .map_err(|e| format!("glTF mesh {}: {}", mesh.index(), e))?,
+            );
+        }
+    }
+    for child in node.children() {
+        import_node(&child, world, blob, meshes)?;
+    }
+    Ok(())
+}
+
+fn import_primitive(
+    primitive: &Primitive,
+    world: Matrix4<f32>,
+    blob: &[u8],
+) -> Result<Mesh, String> {
+    let topology = match primitive.mode() {
+        Mode::Triangles => Topology::TriangleList,
+        Mode::TriangleStrip => Topology::TriangleStrip,
+        Mode::Lines => Topology::LineList,
+        Mode::Points => Topology::PointList,
+        mode => return Err(format!("primitives of mode {:?} are not supported", mode)),
+    };
+    let reader = primitive.reader(|buffer| match buffer.source() {
+        buffer::Source::Bin => Some(blob),
+        buffer::Source::Uri(_) => None,
+    });
+
+    let vertices = reader
+        .read_positions()
+        .ok_or_else(|| "a primitive has no positions".to_string())?
+        .map(|p| {
+            let p = world.transform_point(Point3::from(p));
+            Position::Xyz([p.x, p.y, p.z])
+        })
+        .collect();
+    // Normals go through the inverse transpose, which keeps them perpendicular to the surface
+    // under non-uniform scaling.
+    let normal_matrix = world.invert().unwrap_or(world).transpose();
+    let normals = reader.read_normals().map(|normals| {
+        normals
+            .map(|n| {
+                let n = normal_matrix.transform_vector(Vector3::from(n)).normalize();
+                [n.x, n.y, n.z]
+            })
+            .collect()
+    });
+    let colors = reader
+        .read_colors(0)
+        .map(|colors| colors.into_rgba_f32().collect());
+    let uvs = reader
+        .read_tex_coords(0)
+        .map(|uvs| uvs.into_f32().collect());
+    let indices = reader
+        .read_indices()
+        .map(|indices| indices.into_u32().collect());
+
+    let pbr = primitive.material().pbr_metallic_roughness();
+    let texture = match pbr.base_color_texture() {
+        Some(ref info) if info.tex_coord() != 0 => {
+            return Err("only the first set of texture coordinates is supported".to_string())
+        }
+        Some(info) => Some(info.texture().index()),
+        None => None,
+    };
+
+    Ok(Mesh {
+        vertices,
+        normals,
+        colors,
+        texture: if uvs.is_some() { texture } else { None },
+        uvs,
+        indices,
+        topology,
+        color: Some(pbr.base_color_factor()),
+        ..Mesh::default()
+    })
+}
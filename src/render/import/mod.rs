@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+use super::mesh::Mesh;
+use super::texture::TextureSpec;
+
+mod glb;
+mod obj;
+
+/// A model file whose meshes are drawn instead of meshes given in JSON. Files are base64, like
+/// textures.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum Model {
+    /// A Wavefront OBJ file. The MTL files and textures it refers to are keyed by the names it
+    /// uses for them.
+    Obj {
+        data: String,
+        #[serde(default)]
+        materials: HashMap<String, String>,
+        #[serde(default)]
+        textures: HashMap<String, String>,
+    },
+    /// A glTF 2.0 binary file, with its buffers and images embedded.
+    Glb { data: String },
+}
+
+/// What a model file turns into. The meshes' `texture` indices point into `textures`.
+pub struct Imported {
+    pub meshes: Vec<Mesh>,
+    pub textures: Vec<TextureSpec>,
+}
+
+impl Model {
+    /// Parses the file into meshes and textures, or explains what about it isn't supported.
+    pub fn import(&self) -> Result<Imported, String> {
+        match self {
+            Model::Obj {
+                data,
+                materials,
+                textures,
+            } => obj::import(&decode(data, "model")?, materials, textures),
+            Model::Glb { data } => glb::import(&decode(data, "model")?),
+        }
+    }
+}
+
+fn decode(data: &str, what: &str) -> Result<Vec<u8>, String> {
+    base64::decode(data).map_err(|e| format!("{} is not base64: {}", what, e))
+}
@@ -0,0 +1,120 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::Path;
+
+use super::{decode, Imported};
+use crate::render::mesh::{Mesh, Position};
+use crate::render::texture::TextureSpec;
+
+/// Imports an OBJ file, with `materials` holding the MTL files and `textures` the images its
+/// materials refer to, all base64 and keyed by name. Faces are triangulated.
+pub fn import(
+    data: &[u8],
+    materials: &HashMap<String, String>,
+    textures: &HashMap<String, String>,
+) -> Result<Imported, String> {
+    // The loader can only fail without saying why, so it keeps track of what was missing.
+    let missing = RefCell::new(None);
+    let loaded = tobj::load_obj_buf(&mut Cursor::new(data), |path: &Path| {
+        let name = path.to_string_lossy().into_owned();
+        match materials.get(&name).map(|mtl| decode(mtl, &name)) {
+            Some(Ok(mtl)) => tobj::load_mtl_buf(&mut Cursor::new(mtl)),
+            Some(Err(err)) => {
+                *missing.borrow_mut() = Some(err);
+                Err(tobj::LoadError::OpenFileFailed)
+            }
+            None => {
+                *missing.borrow_mut() = Some(format!("material library `{}` was not given", name));
+                Err(tobj::LoadError::OpenFileFailed)
+            }
+        }
+    });
+    let (models, obj_materials) = match loaded {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            return Err(missing
+                .into_inner()
+                .unwrap_or_else(|| format!("could not parse OBJ file: {:?}", err)))
+        }
+    };
+    if models.is_empty() {
+        return Err("OBJ file has no faces".to_string());
+    }
+
+    // Textures are shared by the materials that use the same file.
+    let mut texture_specs = Vec::new();
+    let mut texture_indices = HashMap::new();
+    let mut material_textures = Vec::with_capacity(obj_materials.len());
+    for material in &obj_materials {
+        if material.diffuse_texture.is_empty() {
+            material_textures.push(None);
+            continue;
+        }
+        let name = &material.diffuse_texture;
+        let index = match texture_indices.get(name) {
+            Some(&index) => index,
+            None => {
+                let data = textures.get(name).ok_or_else(|| {
+                    format!(
+                        "texture `{}` of material `{}` was not given",
+                        name, material.name
+                    )
+                })?;
+                texture_specs.push(TextureSpec {
+                    data: data.clone(),
+                    sampler: Default::default(),
+                });
+                texture_indices.insert(name.clone(), texture_specs.len() - 1);
+                texture_specs.len() - 1
+            }
+        };
+        material_textures.push(Some(index));
+    }
+
+    let meshes = models
+        .into_iter()
+        .map(|model| {
+            let mesh = model.mesh;
+            let uvs = if mesh.texcoords.is_empty() {
+                None
+            } else {
+                // OBJ puts v = 0 at the bottom of the image, Vulkan at the top.
+                Some(
+                    mesh.texcoords
+                        .chunks(2)
+                        .map(|uv| [uv[0], 1.0 - uv[1]])
+                        .collect(),
+                )
+            };
+            let material = mesh.material_id.map(|id| &obj_materials[id]);
+            let texture = mesh.material_id.and_then(|id| material_textures[id]);
+            Mesh {
+                vertices: mesh
+                    .positions
+                    .chunks(3)
+                    .map(|p| Position::Xyz([p[0], p[1], p[2]]))
+                    .collect(),
+                normals: if mesh.normals.is_empty() {
+                    None
+                } else {
+                    Some(mesh.normals.chunks(3).map(|n| [n[0], n[1], n[2]]).collect())
+                },
+                color: material.map(|m| {
+                    let [r, g, b] = m.diffuse;
+                    [r, g, b, m.dissolve]
+                }),
+                // Without texture coordinates there is nothing to sample it with.
+                texture: if uvs.is_some() { texture } else { None },
+                uvs,
+                indices: Some(mesh.indices),
+                ..Mesh::default()
+            }
+        })
+        .collect();
+
+    Ok(Imported {
+        meshes,
+        textures: texture_specs,
+    })
+}
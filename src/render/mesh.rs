@@ -0,0 +1,240 @@
+use super::transform::Transform;
+
+/// Color of vertices when the mesh doesn't give any.
+pub const DEFAULT_COLOR: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
+
+/// A vertex position, with `z` being 0 when only `x` and `y` are given.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[serde(untagged)]
+pub enum Position {
+    Xy([f32; 2]),
+    Xyz([f32; 3]),
+}
+
+impl Position {
+    pub fn xyz(self) -> [f32; 3] {
+        match self {
+            Position::Xy([x, y]) => [x, y, 0.0],
+            Position::Xyz(xyz) => xyz,
+        }
+    }
+}
+
+/// Geometry drawn with a single draw call: vertex positions, optionally with per-vertex
+/// attributes and an index buffer, along with where it is placed and how it is shaded.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Mesh {
+    #[serde(default)]
+    pub vertices: Vec<Position>,
+    /// One normal per vertex, passed along to the fragment stage; `[0, 0, 1]` when left out.
+    #[serde(default)]
+    pub normals: Option<Vec<[f32; 3]>>,
+    /// One RGBA color per vertex; every vertex is `DEFAULT_COLOR` when left out.
+    #[serde(default)]
+    pub colors: Option<Vec<[f32; 4]>>,
+    /// One texture coordinate per vertex, needed to draw with a texture.
+    #[serde(default)]
+    pub uvs: Option<Vec<[f32; 2]>>,
+    /// Indices into `vertices`; when left out, vertices are drawn in order.
+    #[serde(default)]
+    pub indices: Option<Vec<u32>>,
+    #[serde(default)]
+    pub topology: Topology,
+    /// Places the vertices in the world.
+    #[serde(default)]
+    pub transform: Option<Transform>,
+    /// The material color, multiplied with the vertex colors.
+    #[serde(default)]
+    pub color: Option<[f32; 4]>,
+    /// Index of the texture to draw with, whose samples are multiplied with the colors. Meshes
+    /// with `uvs` use the first texture when left out.
+    #[serde(default)]
+    pub texture: Option<usize>,
+    /// Meshes are drawn by ascending `order`, then in the order they are given.
+    #[serde(default)]
+    pub order: i32,
+    #[serde(default)]
+    pub cull_mode: CullMode,
+    #[serde(default)]
+    pub front_face: FrontFace,
+}
+
+/// Which faces are discarded.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum CullMode {
+    None,
+    Front,
+    Back,
+}
+
+impl Default for CullMode {
+    fn default() -> CullMode {
+        CullMode::None
+    }
+}
+
+/// The winding of front faces as seen in the image.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum FrontFace {
+    CounterClockwise,
+    Clockwise,
+}
+
+impl Default for FrontFace {
+    fn default() -> FrontFace {
+        FrontFace::CounterClockwise
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Topology {
+    TriangleList,
+    TriangleStrip,
+    LineList,
+    PointList,
+}
+
+impl Default for Topology {
+    fn default() -> Topology {
+        Topology::TriangleList
+    }
+}
+
+impl Topology {
+    /// Checks that `count` vertices form whole primitives.
+    fn check_count(self, count: usize) -> Result<(), String> {
+        let valid = match self {
+            Topology::TriangleList => count % 3 == 0,
+            Topology::TriangleStrip => count >= 3,
+            Topology::LineList => count % 2 == 0,
+            Topology::PointList => true,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("{} vertices don't form a {:?}", count, self))
+        }
+    }
+}
+
+impl Mesh {
+    /// Checks everything the GPU would otherwise trip over, given the number of textures of the
+    /// request.
+    pub fn validate(&self, textures: usize) -> Result<(), String> {
+        if self.vertices.is_empty() {
+            return Err("at least one vertex is needed".to_string());
+        }
+        if self
+            .vertices
+            .iter()
+            .any(|v| v.xyz().iter().any(|c| !c.is_finite()))
+        {
+            return Err("vertex positions must be finite".to_string());
+        }
+        if let Some(ref normals) = self.normals {
+            self.check_len(normals.len(), "normals")?;
+            if normals.iter().flat_map(|n| n.iter()).any(|c| !c.is_finite()) {
+                return Err("normals must be finite".to_string());
+            }
+        }
+        if let Some(ref colors) = self.colors {
+            self.check_len(colors.len(), "colors")?;
+            if colors.iter().any(|c| !is_color(c)) {
+                return Err("color components must be between 0 and 1".to_string());
+            }
+        }
+        if let Some(ref uvs) = self.uvs {
+            self.check_len(uvs.len(), "uvs")?;
+            if uvs.iter().flat_map(|uv| uv.iter()).any(|c| !c.is_finite()) {
+                return Err("uvs must be finite".to_string());
+            }
+        }
+
+        if let Some(ref transform) = self.transform {
+            transform.validate()?;
+        }
+        if let Some(ref color) = self.color {
+            if !is_color(color) {
+                return Err("`color` components must be between 0 and 1".to_string());
+            }
+        }
+        if let Some(texture) = self.texture {
+            if texture >= textures {
+                return Err(format!(
+                    "texture {} is out of range for {} textures",
+                    texture, textures
+                ));
+            }
+            if self.uvs.is_none() {
+                return Err("`uvs` are needed to draw with a texture".to_string());
+            }
+        }
+
+        match self.indices {
+            Some(ref indices) => {
+                if indices.is_empty() {
+                    return Err("`indices` must not be empty".to_string());
+                }
+                if let Some(index) = indices
+                    .iter()
+                    .find(|&&i| i as usize >= self.vertices.len())
+                {
+                    return Err(format!(
+                        "index {} is out of range for {} vertices",
+                        index,
+                        self.vertices.len()
+                    ));
+                }
+                self.topology.check_count(indices.len())
+            }
+            None => self.topology.check_count(self.vertices.len()),
+        }
+    }
+
+    fn check_len(&self, len: usize, what: &str) -> Result<(), String> {
+        if len == self.vertices.len() {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} {} given for {} vertices",
+                len,
+                what,
+                self.vertices.len()
+            ))
+        }
+    }
+
+    /// The index of the texture to draw with, if any, given the number of textures of the
+    /// request.
+    pub fn texture(&self, textures: usize) -> Option<usize> {
+        match self.texture {
+            Some(texture) => Some(texture),
+            None if self.uvs.is_some() && textures > 0 => Some(0),
+            None => None,
+        }
+    }
+
+    pub fn normal(&self, vertex: usize) -> [f32; 3] {
+        self.normals
+            .as_ref()
+            .map_or([0.0, 0.0, 1.0], |normals| normals[vertex])
+    }
+
+    pub fn uv(&self, vertex: usize) -> [f32; 2] {
+        self.uvs.as_ref().map_or([0.0, 0.0], |uvs| uvs[vertex])
+    }
+
+    pub fn vertex_color(&self, vertex: usize) -> [f32; 4] {
+        self.colors
+            .as_ref()
+            .map_or(DEFAULT_COLOR, |colors| colors[vertex])
+    }
+}
+
+/// Whether every component of `color` is between 0 and 1.
+pub fn is_color(color: &[f32; 4]) -> bool {
+    color.iter().all(|&c| c >= 0.0 && c <= 1.0)
+}
@@ -0,0 +1,550 @@
+use image::RgbaImage;
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use vulkano::device::Device;
+use vulkano::format::ClearValue;
+use vulkano::format::Format;
+use vulkano::framebuffer::Framebuffer;
+use vulkano::framebuffer::FramebufferAbstract;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::image::AttachmentImage;
+use vulkano::pipeline::depth_stencil::{Compare, DepthStencil};
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+
+use crate::context::{DeviceContext, GpuContext};
+use crate::device::{DeviceSelector, SelectionError};
+use crate::jobs::{Interruption, JobControl};
+
+pub mod animation;
+pub mod import;
+pub mod mesh;
+pub mod output;
+pub mod request;
+pub mod scene;
+pub mod stream;
+pub mod texture;
+pub mod transform;
+
+pub use self::animation::{animate, AnimationFormat, AnimationRequest};
+pub use self::mesh::{CullMode, FrontFace, Topology};
+pub use self::output::OutputFormat;
+pub use self::request::{DepthCompare, DepthTest, RenderRequest};
+pub use self::scene::Scene;
+pub use self::stream::{FrameStream, StreamFormat, Streams};
+
+/// Extent used when a request does not ask for a specific size.
+pub const DEFAULT_DIMENSIONS: [u32; 2] = [1024, 768];
+
+/// Largest extent requests may ask for unless the configuration says otherwise.
+pub const DEFAULT_MAX_DIMENSIONS: [u32; 2] = [4096, 4096];
+
+/// Format of the offscreen color attachment. `R8G8B8A8Unorm` is one of the formats every Vulkan
+/// implementation must support as a color attachment, software ICDs such as lavapipe and
+/// SwiftShader included, and it maps directly onto an `RgbaImage`.
+const COLOR_FORMAT: Format = Format::R8G8B8A8Unorm;
+
+/// Format textures are uploaded in. Like the color attachment it is linear, so that texels end up
+/// in the image unchanged.
+const TEXTURE_FORMAT: Format = Format::R8G8B8A8Unorm;
+
+/// Format of the depth attachment, which every implementation must support as one.
+const DEPTH_FORMAT: Format = Format::D16Unorm;
+
+mod vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec4 color;
+layout(location = 3) in vec2 uv;
+
+layout(location = 0) out vec4 v_color;
+layout(location = 1) out vec2 v_uv;
+layout(location = 2) out vec3 v_normal;
+
+layout(push_constant) uniform PushConstants {
+    mat4 model_view_projection;
+    // The material color, multiplied with the vertex color.
+    vec4 color;
+} mesh;
+
+void main() {
+    gl_Position = mesh.model_view_projection * vec4(position, 1.0);
+    // Only read for point lists, where it would otherwise be undefined.
+    gl_PointSize = 1.0;
+    v_color = color * mesh.color;
+    v_uv = uv;
+    v_normal = normal;
+}
+"]
+    #[allow(dead_code)]
+    struct Dummy;
+}
+
+mod fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec4 v_color;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    f_color = v_color;
+}
+"]
+    #[allow(dead_code)]
+    struct Dummy;
+}
+
+mod fs_textured {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec4 v_color;
+layout(location = 1) in vec2 v_uv;
+
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform sampler2D tex;
+
+void main() {
+    f_color = v_color * texture(tex, v_uv);
+}
+"]
+    #[allow(dead_code)]
+    struct Dummy;
+}
+
+#[derive(Debug, Clone)]
+struct Vertex {
+    position: [f32; 3],
+    normal: [f32; 3],
+    color: [f32; 4],
+    uv: [f32; 2],
+}
+impl_vertex!(Vertex, position, normal, color, uv);
+
+/// What tells the pipelines of a device apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PipelineKey {
+    pub topology: Topology,
+    pub samples: u32,
+    /// Whether there is a depth attachment, and how it's used.
+    pub depth: Option<DepthTest>,
+    pub cull_mode: CullMode,
+    pub front_face: FrontFace,
+    /// Whether the fragment shader samples a texture, bound as set 0.
+    pub textured: bool,
+}
+
+/// Builds the render pass drawing into the color attachment, then the depth attachment if there
+/// is one.
+///
+/// With multisampling we draw into a transient multisampled attachment instead and resolve it
+/// into the single-sampled one, which is the only one that can be copied out. The attachments
+/// are then the multisampled one, the resolved one and the depth one.
+fn build_render_pass(
+    device: &Arc<Device>,
+    samples: u32,
+    depth: bool,
+) -> Result<Arc<dyn RenderPassAbstract + Send + Sync>, RenderError> {
+    let render_pass: Arc<dyn RenderPassAbstract + Send + Sync> = match (samples > 1, depth) {
+        (false, false) => Arc::new(
+            single_pass_renderpass!(device.clone(),
+                attachments: {
+                    color: {
+                        load: Clear,
+                        store: Store,
+                        format: COLOR_FORMAT,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                    color: [color],
+                    depth_stencil: {}
+                }
+            )
+            .map_err(vulkan)?,
+        ),
+        (false, true) => Arc::new(
+            single_pass_renderpass!(device.clone(),
+                attachments: {
+                    color: {
+                        load: Clear,
+                        store: Store,
+                        format: COLOR_FORMAT,
+                        samples: 1,
+                    },
+                    depth: {
+                        load: Clear,
+                        store: DontCare,
+                        format: DEPTH_FORMAT,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                    color: [color],
+                    depth_stencil: {depth}
+                }
+            )
+            .map_err(vulkan)?,
+        ),
+        (true, false) => Arc::new(
+            single_pass_renderpass!(device.clone(),
+                attachments: {
+                    multisampled: {
+                        load: Clear,
+                        store: DontCare,
+                        format: COLOR_FORMAT,
+                        samples: samples,
+                    },
+                    color: {
+                        load: DontCare,
+                        store: Store,
+                        format: COLOR_FORMAT,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                    color: [multisampled],
+                    depth_stencil: {},
+                    resolve: [color]
+                }
+            )
+            .map_err(vulkan)?,
+        ),
+        (true, true) => Arc::new(
+            single_pass_renderpass!(device.clone(),
+                attachments: {
+                    multisampled: {
+                        load: Clear,
+                        store: DontCare,
+                        format: COLOR_FORMAT,
+                        samples: samples,
+                    },
+                    color: {
+                        load: DontCare,
+                        store: Store,
+                        format: COLOR_FORMAT,
+                        samples: 1,
+                    },
+                    depth: {
+                        load: Clear,
+                        store: DontCare,
+                        format: DEPTH_FORMAT,
+                        samples: samples,
+                    }
+                },
+                pass: {
+                    color: [multisampled],
+                    depth_stencil: {depth},
+                    resolve: [color]
+                }
+            )
+            .map_err(vulkan)?,
+        ),
+    };
+    Ok(render_pass)
+}
+
+/// Builds a framebuffer for a render pass from `build_render_pass` around `image`, creating the
+/// other attachments it needs, and the values they are cleared to.
+fn build_framebuffer(
+    device: &Arc<Device>,
+    render_pass: &Arc<dyn RenderPassAbstract + Send + Sync>,
+    image: &Arc<AttachmentImage>,
+    dimensions: [u32; 2],
+    samples: u32,
+    depth: bool,
+    clear_color: [f32; 4],
+) -> Result<(Arc<dyn FramebufferAbstract + Send + Sync>, Vec<ClearValue>), RenderError> {
+    let depth_image = || {
+        AttachmentImage::transient_multisampled(device.clone(), dimensions, samples, DEPTH_FORMAT)
+            .map_err(vulkan)
+    };
+    let framebuffer = Framebuffer::start(render_pass.clone());
+    let built = match (samples > 1, depth) {
+        (false, false) => (
+            Arc::new(
+                framebuffer
+                    .add(image.clone())
+                    .map_err(vulkan)?
+                    .build()
+                    .map_err(vulkan)?,
+            ) as Arc<dyn FramebufferAbstract + Send + Sync>,
+            vec![clear_color.into()],
+        ),
+        (false, true) => (
+            Arc::new(
+                framebuffer
+                    .add(image.clone())
+                    .map_err(vulkan)?
+                    .add(depth_image()?)
+                    .map_err(vulkan)?
+                    .build()
+                    .map_err(vulkan)?,
+            ) as Arc<dyn FramebufferAbstract + Send + Sync>,
+            vec![clear_color.into(), 1.0f32.into()],
+        ),
+        (true, _) => {
+            let multisampled = AttachmentImage::transient_multisampled(
+                device.clone(),
+                dimensions,
+                samples,
+                COLOR_FORMAT,
+            )
+            .map_err(vulkan)?;
+            let framebuffer = framebuffer
+                .add(multisampled)
+                .map_err(vulkan)?
+                .add(image.clone())
+                .map_err(vulkan)?;
+            if depth {
+                (
+                    Arc::new(
+                        framebuffer
+                            .add(depth_image()?)
+                            .map_err(vulkan)?
+                            .build()
+                            .map_err(vulkan)?,
+                    ) as Arc<dyn FramebufferAbstract + Send + Sync>,
+                    vec![clear_color.into(), ClearValue::None, 1.0f32.into()],
+                )
+            } else {
+                (
+                    Arc::new(framebuffer.build().map_err(vulkan)?)
+                        as Arc<dyn FramebufferAbstract + Send + Sync>,
+                    vec![clear_color.into(), ClearValue::None],
+                )
+            }
+        }
+    };
+    Ok(built)
+}
+
+fn compare(compare: DepthCompare) -> Compare {
+    match compare {
+        DepthCompare::Never => Compare::Never,
+        DepthCompare::Less => Compare::Less,
+        DepthCompare::Equal => Compare::Equal,
+        DepthCompare::LessOrEqual => Compare::LessOrEqual,
+        DepthCompare::Greater => Compare::Greater,
+        DepthCompare::NotEqual => Compare::NotEqual,
+        DepthCompare::GreaterOrEqual => Compare::GreaterOrEqual,
+        DepthCompare::Always => Compare::Always,
+    }
+}
+
+/// A render pass and the graphics pipeline drawing into it, built once per device and
+/// `PipelineKey`.
+pub struct RenderPipeline {
+    pub render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    pub pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+}
+
+impl RenderPipeline {
+    fn build(device: &Arc<Device>, key: PipelineKey) -> Result<RenderPipeline, RenderError> {
+        let vs = vs::Shader::load(device.clone()).map_err(vulkan)?;
+
+        let render_pass = build_render_pass(device, key.samples, key.depth.is_some())?;
+
+        let builder = GraphicsPipeline::start()
+            .vertex_input_single_buffer::<Vertex>()
+            .vertex_shader(vs.main_entry_point(), ());
+        let builder = match key.topology {
+            Topology::TriangleList => builder.triangle_list(),
+            Topology::TriangleStrip => builder.triangle_strip(),
+            Topology::LineList => builder.line_list(),
+            Topology::PointList => builder.point_list(),
+        };
+
+        let builder = match key.cull_mode {
+            CullMode::None => builder.cull_mode_disabled(),
+            CullMode::Front => builder.cull_mode_front(),
+            CullMode::Back => builder.cull_mode_back(),
+        };
+        let builder = match key.front_face {
+            FrontFace::CounterClockwise => builder.front_face_counter_clockwise(),
+            FrontFace::Clockwise => builder.front_face_clockwise(),
+        };
+        let builder = match key.depth {
+            Some(depth) => builder.depth_stencil(DepthStencil {
+                depth_compare: compare(depth.compare),
+                depth_write: depth.write,
+                ..DepthStencil::disabled()
+            }),
+            None => builder.depth_stencil_disabled(),
+        };
+
+        let builder = builder.viewports_dynamic_scissors_irrelevant(1);
+        let subpass = Subpass::from(render_pass.clone(), 0)
+            .ok_or_else(|| RenderError::Vulkan("the render pass has no subpass".to_string()))?;
+        let pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync> = if key.textured {
+            let fs = fs_textured::Shader::load(device.clone()).map_err(vulkan)?;
+            Arc::new(
+                builder
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .render_pass(subpass)
+                    .build(device.clone())
+                    .map_err(vulkan)?,
+            )
+        } else {
+            let fs = fs::Shader::load(device.clone()).map_err(vulkan)?;
+            Arc::new(
+                builder
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .render_pass(subpass)
+                    .build(device.clone())
+                    .map_err(vulkan)?,
+            )
+        };
+
+        Ok(RenderPipeline {
+            render_pass,
+            pipeline,
+        })
+    }
+
+    /// Returns the pipeline of `gpu` for `key`, building it on first use.
+    ///
+    /// As in `PipelineCache::get_or_build`, the lock is not held while building, and when two
+    /// requests build the same pipeline the first one stored is kept.
+    fn get(gpu: &DeviceContext, key: PipelineKey) -> Result<Arc<RenderPipeline>, RenderError> {
+        if let Some(pipeline) = gpu.render_pipelines.lock().unwrap().get(&key) {
+            return Ok(pipeline.clone());
+        }
+        let pipeline = Arc::new(RenderPipeline::build(&gpu.device, key)?);
+        let mut pipelines = gpu.render_pipelines.lock().unwrap();
+        Ok(pipelines.entry(key).or_insert(pipeline).clone())
+    }
+}
+
+/// The largest texture the device of `gpu` can sample from that is no larger than `max`.
+pub fn max_texture_size(gpu: &DeviceContext, max: [u32; 2]) -> [u32; 2] {
+    let max_texture = gpu
+        .device
+        .physical_device()
+        .limits()
+        .max_image_dimension_2d();
+    [cmp::min(max_texture, max[0]), cmp::min(max_texture, max[1])]
+}
+
+/// Checks a render of `request` at `dimensions` against the limits of the device of `gpu`, so
+/// that requests it can't handle are refused before they are queued. Textures are checked as they
+/// are decoded, against `max_texture_size`.
+pub fn check_limits(
+    gpu: &DeviceContext,
+    request: &RenderRequest,
+    dimensions: [u32; 2],
+) -> Result<(), String> {
+    let physical = gpu.device.physical_device();
+    let limits = physical.limits();
+    let max_width = cmp::min(limits.max_image_dimension_2d(), limits.max_framebuffer_width());
+    let max_height = cmp::min(limits.max_image_dimension_2d(), limits.max_framebuffer_height());
+    if dimensions[0] > max_width || dimensions[1] > max_height {
+        return Err(format!(
+            "{} supports images of at most {}x{}",
+            physical.name(),
+            max_width,
+            max_height
+        ));
+    }
+    // Bit masks in which bit `n` stands for `2^n` samples.
+    let mut sample_counts = limits.framebuffer_color_sample_counts();
+    if request.depth.is_some() {
+        sample_counts &= limits.framebuffer_depth_sample_counts();
+    }
+    let samples = request.samples();
+    if sample_counts & samples == 0 {
+        let supported: Vec<_> = (0..7)
+            .map(|bit| 1u32 << bit)
+            .filter(|count| sample_counts & count != 0)
+            .map(|count| count.to_string())
+            .collect();
+        return Err(format!(
+            "{} doesn't support {} samples{}, only {}",
+            physical.name(),
+            samples,
+            if request.depth.is_some() { " with depth" } else { "" },
+            supported.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum RenderError {
+    Selection(SelectionError),
+    /// Vulkan failed while rendering a valid request.
+    Vulkan(String),
+    /// The frames were rendered but could not be encoded.
+    Encoding(String),
+    Interrupted(Interruption),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::Selection(err) => write!(f, "{}", err),
+            RenderError::Vulkan(message) => write!(f, "vulkan error: {}", message),
+            RenderError::Encoding(message) => write!(f, "encoding failed: {}", message),
+            RenderError::Interrupted(interruption) => write!(f, "{}", interruption),
+        }
+    }
+}
+
+impl From<SelectionError> for RenderError {
+    fn from(err: SelectionError) -> RenderError {
+        RenderError::Selection(err)
+    }
+}
+
+impl From<Interruption> for RenderError {
+    fn from(interruption: Interruption) -> RenderError {
+        RenderError::Interrupted(interruption)
+    }
+}
+
+fn vulkan<E: Error>(err: E) -> RenderError {
+    RenderError::Vulkan(err.to_string())
+}
+
+fn encoding<E: Error>(err: E) -> RenderError {
+    RenderError::Encoding(err.to_string())
+}
+
+/// Renders `request` into an offscreen image of `dimensions` and returns the pixels. The request
+/// must have passed `RenderRequest::validate`, and `check_limits` for the selected device.
+///
+/// No window, surface or swapchain is involved, so no instance or device extensions are needed
+/// and this runs against any Vulkan implementation that exposes a graphics queue, including
+/// CPU-only ones. Fails if `selector` doesn't lead to such a device, or if `control` interrupts
+/// the wait for the GPU.
+pub fn render(
+    context: &GpuContext,
+    request: &RenderRequest,
+    textures: &[RgbaImage],
+    dimensions: [u32; 2],
+    selector: &DeviceSelector,
+    control: &JobControl,
+) -> Result<RgbaImage, RenderError> {
+    let scene = Scene::prepare(context, request, textures, selector, control)?;
+    let view_projection = transform::view_projection(
+        request.camera.as_ref(),
+        request.projection.as_ref(),
+        dimensions,
+    );
+    scene.draw(dimensions, request.clear_color(), view_projection, control)
+}
@@ -0,0 +1,194 @@
+use image::bmp::BMPEncoder;
+use image::jpeg::JPEGEncoder;
+use image::png::PNGEncoder;
+use image::pnm::{PNMEncoder, PNMSubtype, SampleEncoding};
+use image::{ColorType, DynamicImage, RgbImage, RgbaImage};
+use rocket::http::ContentType;
+
+use super::{encoding, RenderError};
+
+/// JPEG quality used when the request doesn't give one.
+pub const DEFAULT_JPEG_QUALITY: u8 = 90;
+
+/// How a rendered frame is encoded. Formats without an alpha channel (JPEG, BMP, PPM) drop it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    /// `quality` runs from 1 to 100.
+    Jpeg { quality: u8 },
+    Bmp,
+    Ppm,
+    /// The pixels as they come out of the GPU: RGBA, eight bits per channel, rows top to bottom.
+    Rgba,
+}
+
+impl Default for OutputFormat {
+    fn default() -> OutputFormat {
+        OutputFormat::Png
+    }
+}
+
+impl OutputFormat {
+    /// Parses a `format` name, `quality` only being allowed for JPEG.
+    pub fn from_name(name: &str, quality: Option<u8>) -> Result<OutputFormat, String> {
+        let format = match name.to_lowercase().as_str() {
+            "png" => OutputFormat::Png,
+            "jpeg" | "jpg" => OutputFormat::Jpeg {
+                quality: check_quality(quality)?,
+            },
+            "bmp" => OutputFormat::Bmp,
+            "ppm" => OutputFormat::Ppm,
+            "rgba" | "raw" => OutputFormat::Rgba,
+            _ => {
+                return Err(format!(
+                    "unknown format `{}`, expected png, jpeg, bmp, ppm or rgba",
+                    name
+                ))
+            }
+        };
+        match format {
+            OutputFormat::Jpeg { .. } => Ok(format),
+            _ if quality.is_some() => Err("`quality` only applies to jpeg".to_string()),
+            _ => Ok(format),
+        }
+    }
+
+    /// The format producing the media type `top/sub`, if any. Wildcards pick PNG, and JPEG gets
+    /// `quality`, which must already have passed `check_quality`.
+    pub fn from_media_type(top: &str, sub: &str, quality: u8) -> Option<OutputFormat> {
+        let format = match (top.to_lowercase().as_str(), sub.to_lowercase().as_str()) {
+            ("*", "*") | ("image", "*") | ("image", "png") => OutputFormat::Png,
+            ("image", "jpeg") => OutputFormat::Jpeg { quality },
+            ("image", "bmp") => OutputFormat::Bmp,
+            ("image", "x-portable-pixmap") => OutputFormat::Ppm,
+            ("application", "octet-stream") => OutputFormat::Rgba,
+            _ => return None,
+        };
+        Some(format)
+    }
+
+    pub fn content_type(self) -> ContentType {
+        match self {
+            OutputFormat::Png => ContentType::PNG,
+            OutputFormat::Jpeg { .. } => ContentType::JPEG,
+            OutputFormat::Bmp => ContentType::BMP,
+            OutputFormat::Ppm => ContentType::new("image", "x-portable-pixmap"),
+            OutputFormat::Rgba => ContentType::Binary,
+        }
+    }
+
+    /// Headers sent along with `image` encoded in this format: raw pixels need their dimensions.
+    pub fn headers(self, image: &RgbaImage) -> Vec<(&'static str, String)> {
+        match self {
+            OutputFormat::Rgba => vec![
+                ("X-Image-Width", image.width().to_string()),
+                ("X-Image-Height", image.height().to_string()),
+            ],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn encode(self, image: &RgbaImage) -> Result<Vec<u8>, RenderError> {
+        let (width, height) = image.dimensions();
+        let mut encoded = Vec::new();
+        let result = match self {
+            OutputFormat::Png => {
+                PNGEncoder::new(&mut encoded).encode(image, width, height, ColorType::RGBA(8))
+            }
+            OutputFormat::Jpeg { quality } => JPEGEncoder::new_with_quality(&mut encoded, quality)
+                .encode(&to_rgb(image), width, height, ColorType::RGB(8)),
+            OutputFormat::Bmp => BMPEncoder::new(&mut encoded).encode(
+                &to_rgb(image),
+                width,
+                height,
+                ColorType::RGB(8),
+            ),
+            OutputFormat::Ppm => PNMEncoder::new(&mut encoded)
+                .with_subtype(PNMSubtype::Pixmap(SampleEncoding::Binary))
+                .encode(&to_rgb(image)[..], width, height, ColorType::RGB(8)),
+            OutputFormat::Rgba => {
+                encoded.extend_from_slice(image);
+                Ok(())
+            }
+        };
+        result.map_err(encoding)?;
+        Ok(encoded)
+    }
+}
+
+/// Checks a JPEG quality, defaulting it when missing.
+pub fn check_quality(quality: Option<u8>) -> Result<u8, String> {
+    match quality {
+        None => Ok(DEFAULT_JPEG_QUALITY),
+        Some(quality) if quality >= 1 && quality <= 100 => Ok(quality),
+        Some(_) => Err("`quality` must be between 1 and 100".to_string()),
+    }
+}
+
+fn to_rgb(image: &RgbaImage) -> RgbImage {
+    DynamicImage::ImageRgba8(image.clone()).to_rgb()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_parsed() {
+        assert_eq!(OutputFormat::from_name("PNG", None), Ok(OutputFormat::Png));
+        assert_eq!(
+            OutputFormat::from_name("jpg", None),
+            Ok(OutputFormat::Jpeg {
+                quality: DEFAULT_JPEG_QUALITY
+            })
+        );
+        assert_eq!(
+            OutputFormat::from_name("jpeg", Some(40)),
+            Ok(OutputFormat::Jpeg { quality: 40 })
+        );
+        assert_eq!(OutputFormat::from_name("raw", None), Ok(OutputFormat::Rgba));
+        assert!(OutputFormat::from_name("tiff", None).is_err());
+    }
+
+    #[test]
+    fn quality_only_applies_to_jpeg() {
+        assert!(OutputFormat::from_name("png", Some(40)).is_err());
+        assert!(OutputFormat::from_name("jpeg", Some(0)).is_err());
+        assert!(OutputFormat::from_name("jpeg", Some(101)).is_err());
+    }
+
+    #[test]
+    fn quality_defaults_and_bounds() {
+        assert_eq!(check_quality(None), Ok(DEFAULT_JPEG_QUALITY));
+        assert_eq!(check_quality(Some(1)), Ok(1));
+        assert_eq!(check_quality(Some(100)), Ok(100));
+        assert!(check_quality(Some(0)).is_err());
+        assert!(check_quality(Some(101)).is_err());
+    }
+
+    #[test]
+    fn media_types_are_matched() {
+        assert_eq!(
+            OutputFormat::from_media_type("*", "*", 50),
+            Some(OutputFormat::Png)
+        );
+        assert_eq!(
+            OutputFormat::from_media_type("image", "*", 50),
+            Some(OutputFormat::Png)
+        );
+        assert_eq!(
+            OutputFormat::from_media_type("Image", "JPEG", 50),
+            Some(OutputFormat::Jpeg { quality: 50 })
+        );
+        assert_eq!(
+            OutputFormat::from_media_type("image", "x-portable-pixmap", 50),
+            Some(OutputFormat::Ppm)
+        );
+        assert_eq!(
+            OutputFormat::from_media_type("application", "octet-stream", 50),
+            Some(OutputFormat::Rgba)
+        );
+        assert_eq!(OutputFormat::from_media_type("text", "html", 50), None);
+        assert_eq!(OutputFormat::from_media_type("image", "webp", 50), None);
+    }
+}
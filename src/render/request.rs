@@ -0,0 +1,214 @@
+use image::RgbaImage;
+use std::slice;
+
+use super::import::Model;
+use super::mesh::Mesh;
+use super::texture::{TextureSpec, MAX_TEXTURES};
+use super::transform::{Camera, Projection};
+use crate::device::DeviceSelector;
+
+/// Most vertices a single request may upload, over all its meshes.
+pub const MAX_VERTICES: usize = 1 << 20;
+
+/// Most meshes a single request may draw.
+pub const MAX_MESHES: usize = 256;
+
+/// Background of the image when the request doesn't give one.
+pub const DEFAULT_CLEAR_COLOR: [f32; 4] = [0.0, 0.0, 1.0, 1.0];
+
+/// Sample counts Vulkan can express; which of them a device supports is up to the device.
+const SAMPLE_COUNTS: [u32; 7] = [1, 2, 4, 8, 16, 32, 64];
+
+/// What to draw: a scene of `meshes`, or a single mesh given inline, and where to look at it
+/// from. Without `transform`, `camera` and `projection` positions are in normalized device
+/// coordinates.
+#[derive(Deserialize, Serialize)]
+pub struct RenderRequest {
+    /// The mesh given by the fields of the request itself, when `meshes` is left out.
+    #[serde(flatten)]
+    pub mesh: Mesh,
+    #[serde(default)]
+    pub meshes: Vec<Mesh>,
+    /// A model file to take the meshes from instead, see `import_model`.
+    #[serde(default)]
+    pub model: Option<Model>,
+    /// Images the meshes can be textured with.
+    #[serde(default)]
+    pub textures: Vec<TextureSpec>,
+    /// Size of the image; `render.width` and `render.height` from the configuration when left
+    /// out.
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    /// RGBA in `0..1`, `DEFAULT_CLEAR_COLOR` when left out.
+    #[serde(default)]
+    pub clear_color: Option<[f32; 4]>,
+    #[serde(default)]
+    pub camera: Option<Camera>,
+    #[serde(default)]
+    pub projection: Option<Projection>,
+    /// Enables a depth buffer, cleared to 1, when given.
+    #[serde(default)]
+    pub depth: Option<DepthTest>,
+    /// Samples per pixel; above 1 the image is multisampled and resolved before it is read back.
+    #[serde(default)]
+    pub samples: Option<u32>,
+    /// Overrides `render.device` from the configuration.
+    #[serde(default)]
+    pub device: Option<DeviceSelector>,
+}
+
+/// How fragments are tested against and written to the depth buffer.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DepthTest {
+    /// Fragments pass when their depth compares this way to the stored one.
+    #[serde(default)]
+    pub compare: DepthCompare,
+    #[serde(default = "enabled")]
+    pub write: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum DepthCompare {
+    Never,
+    Less,
+    Equal,
+    LessOrEqual,
+    Greater,
+    NotEqual,
+    GreaterOrEqual,
+    /// Turns the test off while still allowing writes.
+    Always,
+}
+
+impl Default for DepthCompare {
+    fn default() -> DepthCompare {
+        DepthCompare::Less
+    }
+}
+
+impl RenderRequest {
+    /// Checks everything the GPU would otherwise trip over, so that bad input is answered with
+    /// a 422 instead of a failed render.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.meshes.is_empty() {
+            self.mesh.validate(self.textures.len())?;
+        } else {
+            if !self.mesh.vertices.is_empty() {
+                return Err("give either `vertices` or `meshes`, not both".to_string());
+            }
+            if self.meshes.len() > MAX_MESHES {
+                return Err(format!("at most {} meshes are allowed", MAX_MESHES));
+            }
+            for (i, mesh) in self.meshes.iter().enumerate() {
+                mesh.validate(self.textures.len())
+                    .map_err(|e| format!("mesh {}: {}", i, e))?;
+            }
+        }
+        let vertices: usize = self.meshes().iter().map(|mesh| mesh.vertices.len()).sum();
+        if vertices > MAX_VERTICES {
+            return Err(format!("at most {} vertices are allowed", MAX_VERTICES));
+        }
+
+        if self.width == Some(0) || self.height == Some(0) {
+            return Err("`width` and `height` must be at least 1".to_string());
+        }
+        if let Some(clear_color) = self.clear_color {
+            if clear_color.iter().any(|&c| !(c >= 0.0 && c <= 1.0)) {
+                return Err("`clear_color` components must be between 0 and 1".to_string());
+            }
+        }
+        if !SAMPLE_COUNTS.contains(&self.samples()) {
+            return Err(format!(
+                "`samples` must be one of {:?}, not {}",
+                SAMPLE_COUNTS,
+                self.samples()
+            ));
+        }
+        if self.textures.len() > MAX_TEXTURES {
+            return Err(format!("at most {} textures are allowed", MAX_TEXTURES));
+        }
+        if let Some(ref camera) = self.camera {
+            camera.validate()?;
+        }
+        if let Some(ref projection) = self.projection {
+            projection.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces `model` by the meshes and textures in it, which are placed by `transform` and
+    /// culled by `cull_mode` and `front_face` like an inline mesh would be. Their textures come
+    /// after those of the request.
+    pub fn import_model(&mut self) -> Result<(), String> {
+        let model = match self.model.take() {
+            Some(model) => model,
+            None => return Ok(()),
+        };
+        if !self.mesh.vertices.is_empty() || !self.meshes.is_empty() {
+            return Err("give either `model`, `vertices` or `meshes`".to_string());
+        }
+        let imported = model.import()?;
+        let offset = self.textures.len();
+        for mut mesh in imported.meshes {
+            mesh.texture = mesh.texture.map(|texture| texture + offset);
+            mesh.transform = self.mesh.transform.clone();
+            mesh.cull_mode = self.mesh.cull_mode;
+            mesh.front_face = self.mesh.front_face;
+            self.meshes.push(mesh);
+        }
+        self.textures.extend(imported.textures);
+        Ok(())
+    }
+
+    /// The meshes to draw, as given.
+    pub fn meshes(&self) -> &[Mesh] {
+        if self.meshes.is_empty() {
+            slice::from_ref(&self.mesh)
+        } else {
+            &self.meshes
+        }
+    }
+
+    /// The meshes to draw, in drawing order, each with its position in `meshes()`.
+    pub fn draw_order(&self) -> Vec<(usize, &Mesh)> {
+        let mut meshes: Vec<_> = self.meshes().iter().enumerate().collect();
+        meshes.sort_by_key(|&(_, mesh)| mesh.order);
+        meshes
+    }
+
+    /// The requested size, falling back to `default` per dimension.
+    pub fn dimensions(&self, default: [u32; 2]) -> [u32; 2] {
+        [
+            self.width.unwrap_or(default[0]),
+            self.height.unwrap_or(default[1]),
+        ]
+    }
+
+    pub fn clear_color(&self) -> [f32; 4] {
+        self.clear_color.unwrap_or(DEFAULT_CLEAR_COLOR)
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples.unwrap_or(1)
+    }
+
+    /// Decodes `textures`, in order, refusing any larger than `max`.
+    pub fn decode_textures(&self, max: [u32; 2]) -> Result<Vec<RgbaImage>, String> {
+        self.textures
+            .iter()
+            .enumerate()
+            .map(|(i, texture)| {
+                texture
+                    .decode(max)
+                    .map_err(|e| format!("texture {}: {}", i, e))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,327 @@
+use cgmath::Matrix4;
+use image::RgbaImage;
+use std::sync::Arc;
+use vulkano::buffer::BufferAccess;
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::command_buffer::DynamicState;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::DescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::image::AttachmentImage;
+use vulkano::image::Dimensions;
+use vulkano::image::ImageUsage;
+use vulkano::image::ImmutableImage;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::sync::now;
+use vulkano::sync::GpuFuture;
+
+use super::mesh::Mesh;
+use super::transform::{self, Transform};
+use super::{
+    build_framebuffer, vs, vulkan, PipelineKey, RenderError, RenderPipeline, RenderRequest,
+    Vertex, COLOR_FORMAT, TEXTURE_FORMAT,
+};
+use crate::context::{DeviceContext, GpuContext};
+use crate::device::{DeviceSelector, QueueKind};
+use crate::jobs::JobControl;
+
+/// Material color of meshes that don't give one.
+const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+
+/// A mesh uploaded to the device, with what it is drawn with.
+struct SceneMesh {
+    /// Position of the mesh in the request.
+    index: usize,
+    resources: Arc<RenderPipeline>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    index_buffer: Option<Arc<CpuAccessibleBuffer<[u32]>>>,
+    sets: Vec<Arc<dyn DescriptorSet + Send + Sync>>,
+    model: Matrix4<f32>,
+    color: [f32; 4],
+}
+
+/// The meshes and textures of a request uploaded to a device, ready to be drawn any number of
+/// times.
+pub struct Scene {
+    pub gpu: Arc<DeviceContext>,
+    queue: Arc<Queue>,
+    /// In drawing order.
+    meshes: Vec<SceneMesh>,
+    samples: u32,
+    depth: bool,
+}
+
+impl Scene {
+    /// Uploads the meshes of `request` and the decoded `textures` to the device `selector` leads
+    /// to, and builds the pipelines to draw them. The request must have passed
+    /// `RenderRequest::validate`.
+    pub fn prepare(
+        context: &GpuContext,
+        request: &RenderRequest,
+        textures: &[RgbaImage],
+        selector: &DeviceSelector,
+        control: &JobControl,
+    ) -> Result<Scene, RenderError> {
+        let (gpu, queue) = context.device(selector, QueueKind::Graphics)?;
+        control.check()?;
+        let (images, upload) = upload_textures(&queue, textures)?;
+        let samplers = request
+            .textures
+            .iter()
+            .map(|texture| texture.sampler.build(&gpu.device))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut meshes = Vec::new();
+        for (index, mesh) in request.draw_order() {
+            control.check()?;
+            let texture = mesh.texture(images.len());
+            let key = PipelineKey {
+                topology: mesh.topology,
+                samples: request.samples(),
+                depth: request.depth,
+                cull_mode: mesh.cull_mode,
+                front_face: mesh.front_face,
+                textured: texture.is_some(),
+            };
+            let resources = RenderPipeline::get(&gpu, key)?;
+            let sets = match texture {
+                Some(texture) => {
+                    let set = PersistentDescriptorSet::start(resources.pipeline.clone(), 0)
+                        .add_sampled_image(images[texture].clone(), samplers[texture].clone())
+                        .map_err(vulkan)?
+                        .build()
+                        .map_err(vulkan)?;
+                    vec![Arc::new(set) as Arc<dyn DescriptorSet + Send + Sync>]
+                }
+                None => Vec::new(),
+            };
+            meshes.push(upload_mesh(&gpu, index, mesh, resources, sets)?);
+        }
+
+        // Waiting for the textures here leaves the scene holding nothing but its resources.
+        let future = upload.then_signal_fence_and_flush().map_err(vulkan)?;
+        control.wait(&future)?.map_err(vulkan)?;
+
+        Ok(Scene {
+            gpu,
+            queue,
+            meshes,
+            samples: request.samples(),
+            depth: request.depth.is_some(),
+        })
+    }
+
+    /// The number of meshes in the scene.
+    pub fn mesh_count(&self) -> usize {
+        self.meshes.len()
+    }
+
+    /// Moves and recolors the mesh at `index` in the request, for the frames drawn from now on.
+    /// Parts left out stay as they are.
+    pub fn update_mesh(
+        &mut self,
+        index: usize,
+        transform: Option<&Transform>,
+        color: Option<[f32; 4]>,
+    ) -> Result<(), String> {
+        let count = self.meshes.len();
+        let mesh = self
+            .meshes
+            .iter_mut()
+            .find(|mesh| mesh.index == index)
+            .ok_or_else(|| format!("mesh {} is out of range for {} meshes", index, count))?;
+        if let Some(transform) = transform {
+            mesh.model = transform.matrix();
+        }
+        if let Some(color) = color {
+            mesh.color = color;
+        }
+        Ok(())
+    }
+
+    /// Draws every mesh, transformed by `view_projection` after its own model matrix, into an
+    /// image of `dimensions` cleared to `clear_color`, all in a single command buffer, and
+    /// returns the pixels.
+    pub fn draw(
+        &self,
+        dimensions: [u32; 2],
+        clear_color: [f32; 4],
+        view_projection: Matrix4<f32>,
+        control: &JobControl,
+    ) -> Result<RgbaImage, RenderError> {
+        control.check()?;
+        let device = self.gpu.device.clone();
+
+        // Instead of the images of a swapchain we draw into a plain attachment image. It also has
+        // to be usable as a transfer source so that we can copy it out once the render pass is
+        // done.
+        let image = AttachmentImage::with_usage(
+            device.clone(),
+            dimensions,
+            COLOR_FORMAT,
+            ImageUsage {
+                transfer_source: true,
+                ..ImageUsage::none()
+            },
+        )
+        .map_err(vulkan)?;
+
+        // The pipelines of all meshes have compatible render passes, as they only differ in what
+        // doesn't affect the attachments.
+        let (framebuffer, clear_values) = build_framebuffer(
+            &device,
+            &self.meshes[0].resources.render_pass,
+            &image,
+            dimensions,
+            self.samples,
+            self.depth,
+            clear_color,
+        )?;
+
+        // The buffer the GPU copies the finished image into, four bytes per pixel.
+        let output = CpuAccessibleBuffer::from_iter(
+            device.clone(),
+            BufferUsage::all(),
+            (0..dimensions[0] * dimensions[1] * 4).map(|_| 0u8),
+        )
+        .map_err(vulkan)?;
+
+        let dynamic_state = DynamicState {
+            line_width: None,
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            scissors: None,
+        };
+
+        let mut builder =
+            AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), self.queue.family())
+                .map_err(vulkan)?
+                .begin_render_pass(framebuffer.clone(), false, clear_values)
+                .map_err(vulkan)?;
+
+        for mesh in &self.meshes {
+            let push_constants = vs::ty::PushConstants {
+                model_view_projection: (view_projection * mesh.model).into(),
+                color: mesh.color,
+            };
+            let vertex_buffers =
+                vec![mesh.vertex_buffer.clone() as Arc<dyn BufferAccess + Send + Sync>];
+            builder = match mesh.index_buffer {
+                Some(ref index_buffer) => builder
+                    .draw_indexed(
+                        mesh.resources.pipeline.clone(),
+                        &dynamic_state,
+                        vertex_buffers,
+                        index_buffer.clone(),
+                        mesh.sets.clone(),
+                        push_constants,
+                    )
+                    .map_err(vulkan)?,
+                None => builder
+                    .draw(
+                        mesh.resources.pipeline.clone(),
+                        &dynamic_state,
+                        vertex_buffers,
+                        mesh.sets.clone(),
+                        push_constants,
+                    )
+                    .map_err(vulkan)?,
+            };
+        }
+
+        let command_buffer = builder
+            .end_render_pass()
+            .map_err(vulkan)?
+            .copy_image_to_buffer(image.clone(), output.clone())
+            .map_err(vulkan)?
+            .build()
+            .map_err(vulkan)?;
+
+        // There is nothing to present, so we simply wait on a fence for the copy to finish.
+        let future = now(device.clone())
+            .then_execute(self.queue.clone(), command_buffer)
+            .map_err(vulkan)?
+            .then_signal_fence_and_flush()
+            .map_err(vulkan)?;
+        control.wait(&future)?.map_err(vulkan)?;
+
+        let pixels = output.read().map_err(vulkan)?;
+        RgbaImage::from_raw(dimensions[0], dimensions[1], pixels.to_vec()).ok_or_else(|| {
+            RenderError::Vulkan("the output buffer does not match the image dimensions".to_string())
+        })
+    }
+}
+
+fn upload_mesh(
+    gpu: &DeviceContext,
+    index: usize,
+    mesh: &Mesh,
+    resources: Arc<RenderPipeline>,
+    sets: Vec<Arc<dyn DescriptorSet + Send + Sync>>,
+) -> Result<SceneMesh, RenderError> {
+    let vertex_buffer = CpuAccessibleBuffer::from_iter(
+        gpu.device.clone(),
+        BufferUsage::all(),
+        mesh.vertices
+            .iter()
+            .enumerate()
+            .map(|(i, &position)| Vertex {
+                position: position.xyz(),
+                normal: mesh.normal(i),
+                color: mesh.vertex_color(i),
+                uv: mesh.uv(i),
+            }),
+    )
+    .map_err(vulkan)?;
+    let index_buffer = match mesh.indices {
+        Some(ref indices) => Some(
+            CpuAccessibleBuffer::from_iter(
+                gpu.device.clone(),
+                BufferUsage::all(),
+                indices.iter().cloned(),
+            )
+            .map_err(vulkan)?,
+        ),
+        None => None,
+    };
+    Ok(SceneMesh {
+        index,
+        resources,
+        vertex_buffer,
+        index_buffer,
+        sets,
+        model: transform::model(mesh.transform.as_ref()),
+        color: mesh.color.unwrap_or(WHITE),
+    })
+}
+
+/// Starts uploading `textures` to the device of `queue`. The images can be used by commands
+/// executed after the returned future.
+fn upload_textures(
+    queue: &Arc<Queue>,
+    textures: &[RgbaImage],
+) -> Result<(Vec<Arc<ImmutableImage<Format>>>, Box<dyn GpuFuture>), RenderError> {
+    let mut images = Vec::with_capacity(textures.len());
+    let mut upload = Box::new(now(queue.device().clone())) as Box<dyn GpuFuture>;
+    for texture in textures {
+        let (image, future) = ImmutableImage::from_iter(
+            texture.iter().cloned(),
+            Dimensions::Dim2d {
+                width: texture.width(),
+                height: texture.height(),
+            },
+            TEXTURE_FORMAT,
+            queue.clone(),
+        )
+        .map_err(vulkan)?;
+        images.push(image);
+        upload = Box::new(upload.join(future));
+    }
+    Ok((images, upload))
+}
@@ -0,0 +1,254 @@
+use image::RgbaImage;
+use rocket::http::ContentType;
+use std::cmp;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::animation::AnimationRequest;
+use super::output::{check_quality, OutputFormat};
+use super::scene::Scene;
+use super::transform;
+use super::RenderError;
+use crate::config::StreamsConfig;
+use crate::jobs::JobControl;
+
+/// Separates the parts of an MJPEG stream.
+const BOUNDARY: &str = "frame";
+
+/// Filler sent after every frame. Responses pass through fixed-size buffers on their way out, so
+/// without it the end of a frame would only leave along with the start of the next one.
+const PADDING: usize = 16 * 1024;
+
+/// How the frames of a stream are sent, always as JPEGs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamFormat {
+    /// `multipart/x-mixed-replace`, one JPEG per part, which browsers show in place.
+    Mjpeg { quality: u8 },
+    /// Server-sent events whose data is a base64 JPEG `data:` URL and whose id is the number of
+    /// the frame.
+    Sse { quality: u8 },
+}
+
+impl StreamFormat {
+    pub fn from_name(name: &str, quality: Option<u8>) -> Result<StreamFormat, String> {
+        let quality = check_quality(quality)?;
+        match name.to_lowercase().as_str() {
+            "mjpeg" => Ok(StreamFormat::Mjpeg { quality }),
+            "sse" => Ok(StreamFormat::Sse { quality }),
+            _ => Err(format!("unknown format `{}`, expected mjpeg or sse", name)),
+        }
+    }
+
+    /// The format producing the media type `top/sub`, if any. Wildcards pick MJPEG, and
+    /// `quality` must already have passed `check_quality`.
+    pub fn from_media_type(top: &str, sub: &str, quality: u8) -> Option<StreamFormat> {
+        match (top.to_lowercase().as_str(), sub.to_lowercase().as_str()) {
+            ("*", "*") | ("multipart", "x-mixed-replace") => Some(StreamFormat::Mjpeg { quality }),
+            ("text", "event-stream") => Some(StreamFormat::Sse { quality }),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> ContentType {
+        match self {
+            StreamFormat::Mjpeg { .. } => {
+                ContentType::with_params("multipart", "x-mixed-replace", ("boundary", BOUNDARY))
+            }
+            StreamFormat::Sse { .. } => ContentType::new("text", "event-stream"),
+        }
+    }
+
+    /// Frame number `index` as it is sent, padding included.
+    fn encode(self, index: u64, image: &RgbaImage) -> Result<Vec<u8>, RenderError> {
+        let mut encoded = match self {
+            StreamFormat::Mjpeg { quality } => {
+                let jpeg = OutputFormat::Jpeg { quality }.encode(image)?;
+                let mut part = format!(
+                    "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                    BOUNDARY,
+                    jpeg.len()
+                )
+                .into_bytes();
+                part.extend_from_slice(&jpeg);
+                part.extend_from_slice(b"\r\n");
+                part
+            }
+            StreamFormat::Sse { quality } => {
+                let jpeg = OutputFormat::Jpeg { quality }.encode(image)?;
+                format!(
+                    "id: {}\ndata: data:image/jpeg;base64,{}\n\n:",
+                    index,
+                    base64::encode(&jpeg)
+                )
+                .into_bytes()
+            }
+        };
+        // Trailing spaces belong to the part in MJPEG, after the end of the JPEG, and to a
+        // comment in SSE; both are ignored.
+        encoded.extend((0..PADDING).map(|_| b' '));
+        let end: &[u8] = match self {
+            StreamFormat::Mjpeg { .. } => b"\r\n",
+            StreamFormat::Sse { .. } => b"\n",
+        };
+        encoded.extend_from_slice(end);
+        Ok(encoded)
+    }
+}
+
+/// Draws frame number `index` of a stream, waiting on the GPU through the given control.
+pub type FrameSource = Box<dyn FnMut(u64, &JobControl) -> Result<RgbaImage, RenderError>>;
+
+/// Frames drawn one after the other at a steady rate, encoded as they are read. The stream ends
+/// when its time is up; a client going away ends it earlier, as nobody reads it anymore.
+pub struct FrameStream {
+    source: FrameSource,
+    format: StreamFormat,
+    control: JobControl,
+    interval: Duration,
+    /// When the next frame is due.
+    due: Instant,
+    /// Frames drawn so far.
+    drawn: u64,
+    /// The frame being sent, from `position` on.
+    pending: Vec<u8>,
+    position: usize,
+    _slot: StreamSlot,
+}
+
+impl FrameStream {
+    /// A stream of `fps` frames per second from `source`, ended by `control`.
+    pub fn new(
+        source: FrameSource,
+        format: StreamFormat,
+        fps: f32,
+        control: JobControl,
+        slot: StreamSlot,
+    ) -> FrameStream {
+        FrameStream {
+            source,
+            format,
+            control,
+            interval: Duration::from_nanos((1e9 / f64::from(fps)) as u64),
+            due: Instant::now(),
+            drawn: 0,
+            pending: Vec::new(),
+            position: 0,
+            _slot: slot,
+        }
+    }
+
+    /// Plays an animation over and over from its uploaded `scene`, at the animation's `fps`.
+    pub fn animation(
+        scene: Scene,
+        request: AnimationRequest,
+        dimensions: [u32; 2],
+        format: StreamFormat,
+        control: JobControl,
+        slot: StreamSlot,
+    ) -> FrameStream {
+        let view_projection = transform::view_projection(
+            request.scene.camera.as_ref(),
+            request.scene.projection.as_ref(),
+            dimensions,
+        );
+        let clear_color = request.scene.clear_color();
+        let fps = request.fps;
+        let source = Box::new(move |index: u64, control: &JobControl| {
+            let frame = (index % u64::from(request.frames)) as u32;
+            scene.draw(
+                dimensions,
+                clear_color,
+                view_projection * request.transform(frame),
+                control,
+            )
+        });
+        FrameStream::new(source, format, fps, control, slot)
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        self.format.content_type()
+    }
+
+    /// Waits until the next frame is due and draws it, or returns `None` once the stream is
+    /// over.
+    fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let now = Instant::now();
+        if self.due > now {
+            thread::sleep(self.due - now);
+        }
+        if self.control.interruption().is_some() {
+            return Ok(None);
+        }
+        let (format, index) = (self.format, self.drawn);
+        let encoded = match (self.source)(index, &self.control)
+            .and_then(|image| format.encode(index, &image))
+        {
+            Ok(encoded) => encoded,
+            Err(RenderError::Interrupted(_)) => return Ok(None),
+            Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+        };
+        // A client reading slower than the frame rate gets fewer frames rather than a backlog.
+        self.due = cmp::max(self.due + self.interval, Instant::now());
+        self.drawn += 1;
+        Ok(Some(encoded))
+    }
+}
+
+impl Read for FrameStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.pending.len() {
+            match self.next_frame()? {
+                Some(encoded) => {
+                    self.pending = encoded;
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let count = cmp::min(buf.len(), self.pending.len() - self.position);
+        buf[..count].copy_from_slice(&self.pending[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+/// Counts the streams being served, so that they can't take up every Rocket worker. Kept in
+/// Rocket's managed state.
+pub struct Streams {
+    active: AtomicUsize,
+    max: usize,
+    /// How long a stream lasts at most.
+    pub timeout: Duration,
+}
+
+/// A place among the streams being served, given back when dropped.
+pub struct StreamSlot(Arc<Streams>);
+
+impl Streams {
+    pub fn new(config: &StreamsConfig) -> Arc<Streams> {
+        Arc::new(Streams {
+            active: AtomicUsize::new(0),
+            max: config.max,
+            timeout: Duration::from_secs(config.timeout),
+        })
+    }
+
+    /// Takes a slot for a new stream, or returns `None` when `streams.max` streams are already
+    /// being served.
+    pub fn acquire(streams: &Arc<Streams>) -> Option<StreamSlot> {
+        if streams.active.fetch_add(1, Ordering::SeqCst) >= streams.max {
+            streams.active.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(StreamSlot(streams.clone()))
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
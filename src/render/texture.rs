@@ -0,0 +1,126 @@
+use image::jpeg::JPEGDecoder;
+use image::png::PNGDecoder;
+use image::{self, ImageDecoder, ImageFormat, RgbaImage};
+use std::sync::Arc;
+use vulkano::device::Device;
+use vulkano::sampler::{BorderColor, Filter, MipmapMode, Sampler, SamplerAddressMode};
+
+use super::{vulkan, RenderError};
+
+/// Most textures a single request may upload.
+pub const MAX_TEXTURES: usize = 16;
+
+/// An image to sample from, as a base64 PNG or JPEG file, and how to sample it.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TextureSpec {
+    pub data: String,
+    #[serde(default)]
+    pub sampler: SamplerSpec,
+}
+
+impl TextureSpec {
+    /// Decodes the image. Anything but PNG and JPEG is refused, and so are images larger than
+    /// `max`, which is read from the file header before any pixel is decoded.
+    pub fn decode(&self, max: [u32; 2]) -> Result<RgbaImage, String> {
+        let bytes =
+            base64::decode(&self.data).map_err(|e| format!("texture is not base64: {}", e))?;
+        let dimensions = match image::guess_format(&bytes) {
+            Ok(ImageFormat::PNG) => PNGDecoder::new(&bytes[..]).dimensions(),
+            Ok(ImageFormat::JPEG) => JPEGDecoder::new(&bytes[..]).dimensions(),
+            _ => return Err("textures must be PNG or JPEG files".to_string()),
+        };
+        let (width, height) =
+            dimensions.map_err(|e| format!("texture could not be decoded: {}", e))?;
+        if width > max[0] || height > max[1] {
+            return Err(format!(
+                "texture is {}x{}, larger than the {}x{} allowed",
+                width, height, max[0], max[1]
+            ));
+        }
+        let decoded = image::load_from_memory(&bytes)
+            .map_err(|e| format!("texture could not be decoded: {}", e))?;
+        Ok(decoded.to_rgba())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterMode {
+    Nearest,
+    Linear,
+}
+
+impl Default for FilterMode {
+    fn default() -> FilterMode {
+        FilterMode::Linear
+    }
+}
+
+impl FilterMode {
+    fn filter(self) -> Filter {
+        match self {
+            FilterMode::Nearest => Filter::Nearest,
+            FilterMode::Linear => Filter::Linear,
+        }
+    }
+}
+
+/// What UVs outside `0..1` sample. Clamping to the border samples transparent black.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressMode {
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+    ClampToBorder,
+}
+
+impl Default for AddressMode {
+    fn default() -> AddressMode {
+        AddressMode::Repeat
+    }
+}
+
+impl AddressMode {
+    fn address_mode(self) -> SamplerAddressMode {
+        match self {
+            AddressMode::Repeat => SamplerAddressMode::Repeat,
+            AddressMode::MirroredRepeat => SamplerAddressMode::MirroredRepeat,
+            AddressMode::ClampToEdge => SamplerAddressMode::ClampToEdge,
+            AddressMode::ClampToBorder => {
+                SamplerAddressMode::ClampToBorder(BorderColor::FloatTransparentBlack)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default)]
+pub struct SamplerSpec {
+    #[serde(default)]
+    pub mag_filter: FilterMode,
+    #[serde(default)]
+    pub min_filter: FilterMode,
+    /// Used for both directions.
+    #[serde(default)]
+    pub address_mode: AddressMode,
+}
+
+impl SamplerSpec {
+    pub fn build(self, device: &Arc<Device>) -> Result<Arc<Sampler>, RenderError> {
+        let address_mode = self.address_mode.address_mode();
+        Sampler::new(
+            device.clone(),
+            self.mag_filter.filter(),
+            self.min_filter.filter(),
+            MipmapMode::Nearest,
+            address_mode,
+            address_mode,
+            address_mode,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        )
+        .map_err(vulkan)
+    }
+}
@@ -0,0 +1,202 @@
+use cgmath::{self, Deg, Euler, InnerSpace, Matrix4, Point3, Quaternion, SquareMatrix, Vector3};
+
+/// Places the model in the world: scaled first, then rotated, then translated.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Transform {
+    #[serde(default)]
+    pub translation: [f32; 3],
+    /// Euler angles in degrees around x, y and z.
+    #[serde(default)]
+    pub rotation: [f32; 3],
+    #[serde(default = "unit_scale")]
+    pub scale: [f32; 3],
+}
+
+fn unit_scale() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+impl Transform {
+    pub fn validate(&self) -> Result<(), String> {
+        let values = self.translation.iter().chain(&self.rotation).chain(&self.scale);
+        check_finite(values, "`transform`")?;
+        if self.scale.iter().any(|&s| s == 0.0) {
+            return Err("`transform.scale` must not be zero".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn matrix(&self) -> Matrix4<f32> {
+        compose(
+            Vector3::from(self.translation),
+            self.quaternion(),
+            Vector3::from(self.scale),
+        )
+    }
+
+    /// The matrix `amount` of the way from `self` to `other`, 0 being `self` and 1 `other`.
+    /// Translation and scale are interpolated linearly and the rotation spherically, taking the
+    /// shorter way around.
+    pub fn interpolate(&self, other: &Transform, amount: f32) -> Matrix4<f32> {
+        let lerp = |from: [f32; 3], to: [f32; 3]| {
+            let from = Vector3::from(from);
+            from + (Vector3::from(to) - from) * amount
+        };
+        let from = self.quaternion();
+        let mut to = other.quaternion();
+        // `q` and `-q` are the same rotation, the one closer to `from` is reached the short way.
+        if from.dot(to) < 0.0 {
+            to = -to;
+        }
+        compose(
+            lerp(self.translation, other.translation),
+            from.slerp(to, amount),
+            lerp(self.scale, other.scale),
+        )
+    }
+
+    fn quaternion(&self) -> Quaternion<f32> {
+        let [x, y, z] = self.rotation;
+        Quaternion::from(Euler::new(Deg(x), Deg(y), Deg(z)))
+    }
+}
+
+/// A camera at `eye` looking at `target`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Camera {
+    pub eye: [f32; 3],
+    pub target: [f32; 3],
+    #[serde(default = "y_up")]
+    pub up: [f32; 3],
+}
+
+fn y_up() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+impl Camera {
+    pub fn validate(&self) -> Result<(), String> {
+        check_finite(self.eye.iter().chain(&self.target).chain(&self.up), "`camera`")?;
+        if self.eye == self.target {
+            return Err("`camera.eye` and `camera.target` must differ".to_string());
+        }
+        if self.up == [0.0, 0.0, 0.0] {
+            return Err("`camera.up` must not be zero".to_string());
+        }
+        Ok(())
+    }
+
+    fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::look_at(
+            Point3::from(self.eye),
+            Point3::from(self.target),
+            Vector3::from(self.up),
+        )
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Projection {
+    /// `fov_y` is the vertical field of view in degrees; the aspect ratio follows the image.
+    Perspective { fov_y: f32, near: f32, far: f32 },
+    Orthographic {
+        left: f32,
+        right: f32,
+        bottom: f32,
+        top: f32,
+        near: f32,
+        far: f32,
+    },
+}
+
+impl Projection {
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Projection::Perspective { fov_y, near, far } => {
+                check_finite([fov_y, near, far].iter(), "`projection`")?;
+                if !(fov_y > 0.0 && fov_y < 180.0) {
+                    return Err("`projection.fov_y` must be between 0 and 180".to_string());
+                }
+                if !(near > 0.0 && far > near) {
+                    return Err("`projection` needs 0 < near < far".to_string());
+                }
+            }
+            Projection::Orthographic {
+                left,
+                right,
+                bottom,
+                top,
+                near,
+                far,
+            } => {
+                check_finite([left, right, bottom, top, near, far].iter(), "`projection`")?;
+                if left == right || bottom == top || near == far {
+                    return Err("`projection` must not have an empty volume".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn matrix(&self, dimensions: [u32; 2]) -> Matrix4<f32> {
+        let projection = match *self {
+            Projection::Perspective { fov_y, near, far } => {
+                let aspect = dimensions[0] as f32 / dimensions[1] as f32;
+                cgmath::perspective(Deg(fov_y), aspect, near, far)
+            }
+            Projection::Orthographic {
+                left,
+                right,
+                bottom,
+                top,
+                near,
+                far,
+            } => cgmath::ortho(left, right, bottom, top, near, far),
+        };
+        // cgmath's projections produce OpenGL's clip space; Vulkan's has y pointing down and
+        // depth running from 0 to 1 instead of -1 to 1.
+        let opengl_to_vulkan = Matrix4::from_translation(Vector3::new(0.0, 0.0, 0.5))
+            * Matrix4::from_nonuniform_scale(1.0, -1.0, 0.5);
+        opengl_to_vulkan * projection
+    }
+}
+
+fn compose(
+    translation: Vector3<f32>,
+    rotation: Quaternion<f32>,
+    scale: Vector3<f32>,
+) -> Matrix4<f32> {
+    Matrix4::from_translation(translation)
+        * Matrix4::from(rotation)
+        * Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z)
+}
+
+/// The model matrix of `transform`, the identity when there is none.
+pub fn model(transform: Option<&Transform>) -> Matrix4<f32> {
+    transform.map_or_else(Matrix4::identity, Transform::matrix)
+}
+
+/// Combines the camera and the projection into the matrix applied after the model matrix.
+/// Missing parts are the identity, so without any of them positions are taken as Vulkan's
+/// normalized device coordinates.
+pub fn view_projection(
+    camera: Option<&Camera>,
+    projection: Option<&Projection>,
+    dimensions: [u32; 2],
+) -> Matrix4<f32> {
+    let view = camera.map_or_else(Matrix4::identity, Camera::matrix);
+    let projection = projection.map_or_else(Matrix4::identity, |p| p.matrix(dimensions));
+    projection * view
+}
+
+fn check_finite<'a, I>(values: I, what: &str) -> Result<(), String>
+where
+    I: IntoIterator<Item = &'a f32>,
+{
+    if values.into_iter().all(|v| v.is_finite()) {
+        Ok(())
+    } else {
+        Err(format!("{} values must be finite", what))
+    }
+}
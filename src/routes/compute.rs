@@ -0,0 +1,38 @@
+use rocket::State;
+use rocket_contrib::{Json, SerdeError};
+use std::sync::Arc;
+
+use super::jobs::{self, Accepted};
+use crate::compute::ComputeRequest;
+use crate::config::{ComputeConfig, RenderConfig};
+use crate::error::Error;
+use crate::jobs::{JobQueue, Work};
+use crate::shaders::{ShaderCache, ShaderStage};
+
+#[post("/", format = "application/json", data = "<job>")]
+pub fn run(
+    job: Result<Json<ComputeRequest>, SerdeError>,
+    defaults: State<RenderConfig>,
+    limits: State<ComputeConfig>,
+    cache: State<ShaderCache>,
+    queue: State<Arc<JobQueue>>,
+) -> Result<Accepted, Error> {
+    let job = job?.into_inner();
+    job.validate(limits.max_bytes)?;
+
+    let (compilation, _) = cache.compile(ShaderStage::Compute, &job.source);
+    if !compilation.succeeded() {
+        return Err(Error::Shader(compilation));
+    }
+    job.check_shader(&compilation)?;
+
+    jobs::submit(
+        &queue,
+        Work::Compute {
+            request: job,
+            shader: compilation,
+            default_device: defaults.device.clone(),
+            max_bytes: limits.max_bytes,
+        },
+    )
+}
@@ -0,0 +1,11 @@
+use rocket::State;
+use rocket_contrib::Json;
+use std::sync::Arc;
+
+use crate::context::GpuContext;
+use crate::device::{self, DeviceInfo};
+
+#[get("/")]
+pub fn list(context: State<Arc<GpuContext>>) -> Json<Vec<DeviceInfo>> {
+    Json(device::enumerate(&context.instance))
+}
@@ -0,0 +1,165 @@
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, status, Content, Responder, Response};
+use rocket::State;
+use rocket_contrib::Json;
+use std::sync::Arc;
+
+use crate::db::DbConn;
+use crate::error::Error;
+use crate::jobs::{JobId, JobInfo, JobKind, JobOutput, JobQueue, JobStatus, Work};
+use crate::models::Job;
+use crate::schema::jobs::dsl::{id, jobs, kind, status as job_status};
+
+/// Jobs listed when the query doesn't give a `limit`.
+const DEFAULT_LIMIT: i64 = 100;
+
+/// Answer to a submission: `202 Accepted` with the job and a `Location` to poll.
+pub struct Accepted(pub JobInfo);
+
+impl<'r> Responder<'r> for Accepted {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let location = format!("/jobs/{}", self.0.id);
+        Response::build_from(Json(self.0).respond_to(request)?)
+            .status(Status::Accepted)
+            .raw_header("Location", location)
+            .ok()
+    }
+}
+
+/// A finished job's output along with its extra headers.
+pub struct Download(Arc<JobOutput>);
+
+impl<'r> Responder<'r> for Download {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let output = self.0;
+        let mut response =
+            Content(output.content_type.clone(), output.body.clone()).respond_to(request)?;
+        for &(name, ref value) in &output.headers {
+            response.set_raw_header(name, value.clone());
+        }
+        Ok(response)
+    }
+}
+
+/// Queues `work`, answering `503` when the queue is full or the job can't be recorded.
+pub fn submit(queue: &JobQueue, work: Work) -> Result<Accepted, Error> {
+    Ok(Accepted(queue.submit(work)?))
+}
+
+fn not_found(job_id: JobId) -> Error {
+    Error::NotFound(format!("job {} not found", job_id))
+}
+
+#[derive(FromForm)]
+pub struct ListQuery {
+    status: Option<String>,
+    kind: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[get("/", rank = 2)]
+pub fn list(queue: State<Arc<JobQueue>>, conn: DbConn) -> Result<Json<Vec<JobInfo>>, Error> {
+    let query = ListQuery {
+        status: None,
+        kind: None,
+        limit: None,
+        offset: None,
+    };
+    list_filtered(query, queue, conn)
+}
+
+/// Lists jobs, newest first. Rows that can't be read are left out with a warning rather than
+/// failing the whole list.
+#[get("/?<query>")]
+pub fn list_filtered(
+    query: ListQuery,
+    queue: State<Arc<JobQueue>>,
+    conn: DbConn,
+) -> Result<Json<Vec<JobInfo>>, Error> {
+    let (limit, offset) = super::page(query.limit, query.offset, DEFAULT_LIMIT)?;
+    let mut statement = jobs
+        .order(id.desc())
+        .limit(limit)
+        .offset(offset)
+        .into_boxed();
+    if let Some(ref wanted) = query.status {
+        let wanted: JobStatus = wanted.parse().map_err(Error::Validation)?;
+        statement = statement.filter(job_status.eq(wanted.name()));
+    }
+    if let Some(ref wanted) = query.kind {
+        let wanted: JobKind = wanted.parse().map_err(Error::Validation)?;
+        statement = statement.filter(kind.eq(wanted.name()));
+    }
+
+    let records = statement.load::<Job>(&*conn)?;
+    let listed = records
+        .iter()
+        .filter_map(|record| match queue.describe(record) {
+            Ok(info) => Some(info),
+            Err(err) => {
+                eprintln!("warning: skipping job: {}", err);
+                None
+            }
+        })
+        .collect();
+    Ok(Json(listed))
+}
+
+#[get("/<job_id>")]
+pub fn get(
+    job_id: JobId,
+    queue: State<Arc<JobQueue>>,
+    conn: DbConn,
+) -> Result<Json<JobInfo>, Error> {
+    let record = jobs
+        .find(job_id)
+        .first::<Job>(&*conn)
+        .optional()?
+        .ok_or_else(|| not_found(job_id))?;
+    let info = queue.describe(&record).map_err(Error::Database)?;
+    Ok(Json(info))
+}
+
+#[get("/<job_id>/result")]
+pub fn result(job_id: JobId, queue: State<Arc<JobQueue>>) -> Result<Download, Error> {
+    match queue.output(job_id) {
+        Some(Ok(output)) => Ok(Download(output)),
+        Some(Err(current)) => Err(Error::Conflict(format!(
+            "job {} has no result, its status is {}",
+            job_id, current
+        ))),
+        None => Err(Error::NotFound(format!(
+            "job {} has no result available",
+            job_id
+        ))),
+    }
+}
+
+/// Cancels a queued or running job. Queued jobs are answered with `200` and already cancelled;
+/// running ones with `202`, as they stop shortly after.
+#[delete("/<job_id>")]
+pub fn cancel(
+    job_id: JobId,
+    queue: State<Arc<JobQueue>>,
+    conn: DbConn,
+) -> Result<status::Custom<Json<JobInfo>>, Error> {
+    let previous = queue.cancel(job_id);
+    let record = jobs
+        .find(job_id)
+        .first::<Job>(&*conn)
+        .optional()?
+        .ok_or_else(|| not_found(job_id))?;
+    let info = queue.describe(&record).map_err(Error::Database)?;
+
+    match previous {
+        Some(JobStatus::Running) => Ok(status::Custom(Status::Accepted, Json(info))),
+        Some(_) => Ok(status::Custom(Status::Ok, Json(info))),
+        None => Err(Error::Conflict(format!(
+            "job {} has already finished, its status is {}",
+            job_id, info.status
+        ))),
+    }
+}
@@ -0,0 +1,56 @@
+use rocket::request::Request;
+use std::cmp;
+
+use crate::error::Error;
+
+pub mod compute;
+pub mod devices;
+pub mod jobs;
+pub mod posts;
+pub mod render;
+pub mod sessions;
+pub mod shaders;
+
+/// Most rows a list answers with, whatever its `limit` asks for.
+const MAX_LIMIT: i64 = 1000;
+
+/// Checks the `limit` and `offset` of a list query, returning the limit to use, `default` when
+/// left out and at most `MAX_LIMIT`, and the offset, 0 when left out.
+pub fn page(limit: Option<i64>, offset: Option<i64>, default: i64) -> Result<(i64, i64), Error> {
+    if limit.map_or(false, |limit| limit < 0) {
+        return Err(Error::Validation("`limit` must not be negative".to_string()));
+    }
+    if offset.map_or(false, |offset| offset < 0) {
+        return Err(Error::Validation("`offset` must not be negative".to_string()));
+    }
+    let limit = cmp::min(limit.unwrap_or(default), MAX_LIMIT);
+    Ok((limit, offset.unwrap_or(0)))
+}
+
+// Catchers for failures that happen before a handler runs, such as a request guard giving up, so
+// that these are answered with the same JSON body as errors returned by handlers.
+
+#[error(400)]
+pub fn bad_request(_: &Request) -> Error {
+    Error::BadRequest("the request could not be understood".to_string())
+}
+
+#[error(404)]
+pub fn not_found(request: &Request) -> Error {
+    Error::NotFound(format!("no route for {} {}", request.method(), request.uri()))
+}
+
+#[error(422)]
+pub fn unprocessable(_: &Request) -> Error {
+    Error::Validation("the request body could not be processed".to_string())
+}
+
+#[error(500)]
+pub fn internal(_: &Request) -> Error {
+    Error::Internal("internal server error".to_string())
+}
+
+#[error(503)]
+pub fn unavailable(_: &Request) -> Error {
+    Error::Unavailable("the service is unavailable, try again later".to_string())
+}
@@ -0,0 +1,135 @@
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use rocket::response::status;
+use rocket_contrib::{Json, SerdeError};
+
+use crate::db::DbConn;
+use crate::error::Error;
+use crate::models::{NewPost, Post, PostChanges};
+use crate::schema::posts::dsl::*;
+
+/// Posts listed when the query doesn't give a `limit`.
+const DEFAULT_LIMIT: i64 = 100;
+
+fn not_found(post_id: i32) -> Error {
+    Error::NotFound(format!("post {} not found", post_id))
+}
+
+#[derive(FromForm)]
+pub struct ListQuery {
+    published: Option<bool>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[get("/", rank = 2)]
+pub fn list(conn: DbConn) -> Result<Json<Vec<Post>>, Error> {
+    let query = ListQuery {
+        published: None,
+        limit: None,
+        offset: None,
+    };
+    list_filtered(query, conn)
+}
+
+#[get("/?<query>")]
+pub fn list_filtered(query: ListQuery, conn: DbConn) -> Result<Json<Vec<Post>>, Error> {
+    let (limit, offset) = super::page(query.limit, query.offset, DEFAULT_LIMIT)?;
+    let mut statement = posts.order(id).limit(limit).offset(offset).into_boxed();
+    if let Some(is_published) = query.published {
+        statement = statement.filter(published.eq(is_published));
+    }
+
+    statement
+        .load::<Post>(&*conn)
+        .map(Json)
+        .map_err(Error::from)
+}
+
+#[get("/<post_id>")]
+pub fn get(post_id: i32, conn: DbConn) -> Result<Json<Post>, Error> {
+    posts
+        .find(post_id)
+        .first::<Post>(&*conn)
+        .optional()?
+        .map(Json)
+        .ok_or_else(|| not_found(post_id))
+}
+
+#[post("/", format = "application/json", data = "<post>")]
+pub fn create(
+    post: Result<Json<NewPost>, SerdeError>,
+    conn: DbConn,
+) -> Result<status::Created<Json<Post>>, Error> {
+    let post = post?.into_inner();
+    if post.title.trim().is_empty() {
+        return Err(Error::Validation("title must not be empty".to_string()));
+    }
+
+    let created = diesel::insert_into(posts)
+        .values(&post)
+        .get_result::<Post>(&*conn)?;
+
+    Ok(status::Created(
+        format!("/posts/{}", created.id),
+        Some(Json(created)),
+    ))
+}
+
+#[patch("/<post_id>", format = "application/json", data = "<changes>")]
+pub fn update(
+    post_id: i32,
+    changes: Result<Json<PostChanges>, SerdeError>,
+    conn: DbConn,
+) -> Result<Json<Post>, Error> {
+    let changes = changes?.into_inner();
+    if changes
+        .title
+        .as_ref()
+        .map_or(false, |t| t.trim().is_empty())
+    {
+        return Err(Error::Validation("title must not be empty".to_string()));
+    }
+
+    match diesel::update(posts.find(post_id))
+        .set(&changes)
+        .get_result::<Post>(&*conn)
+    {
+        Ok(post) => Ok(Json(post)),
+        Err(DieselError::NotFound) => Err(not_found(post_id)),
+        // Diesel refuses to build an `UPDATE` without any columns to set.
+        Err(DieselError::QueryBuilderError(_)) => {
+            Err(Error::Validation("no fields to update".to_string()))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn set_published(post_id: i32, is_published: bool, conn: &DbConn) -> Result<Json<Post>, Error> {
+    diesel::update(posts.find(post_id))
+        .set(published.eq(is_published))
+        .get_result::<Post>(&**conn)
+        .optional()?
+        .map(Json)
+        .ok_or_else(|| not_found(post_id))
+}
+
+#[post("/<post_id>/publish")]
+pub fn publish(post_id: i32, conn: DbConn) -> Result<Json<Post>, Error> {
+    set_published(post_id, true, &conn)
+}
+
+#[post("/<post_id>/unpublish")]
+pub fn unpublish(post_id: i32, conn: DbConn) -> Result<Json<Post>, Error> {
+    set_published(post_id, false, &conn)
+}
+
+#[delete("/<post_id>")]
+pub fn delete(post_id: i32, conn: DbConn) -> Result<status::NoContent, Error> {
+    let deleted = diesel::delete(posts.find(post_id)).execute(&*conn)?;
+
+    if deleted == 0 {
+        return Err(not_found(post_id));
+    }
+    Ok(status::NoContent)
+}
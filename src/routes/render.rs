@@ -0,0 +1,319 @@
+use image::RgbaImage;
+use rocket::http::{Accept, QMediaType};
+use rocket::request::{self, FormItems, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::{Outcome, State};
+use rocket_contrib::{Json, SerdeError};
+use std::sync::Arc;
+
+use super::jobs::{self, Accepted};
+use crate::config::RenderConfig;
+use crate::context::GpuContext;
+use crate::device::{DeviceSelector, QueueKind};
+use crate::error::Error;
+use crate::jobs::{JobControl, JobQueue, Work};
+use crate::render::output::check_quality;
+use crate::render::{
+    self, AnimationFormat, AnimationRequest, FrameStream, OutputFormat, RenderRequest, Scene,
+    StreamFormat, Streams,
+};
+
+/// Picks the output format from the `format` and `quality` query parameters or, without
+/// `format`, from the first media type in `Accept` that can be produced. No `Accept` means PNG.
+impl<'a, 'r> FromRequest<'a, 'r> for OutputFormat {
+    type Error = Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<OutputFormat, Error> {
+        match negotiate(request) {
+            Ok(format) => Outcome::Success(format),
+            Err(err) => Outcome::Failure((err.status(), err)),
+        }
+    }
+}
+
+fn negotiate(request: &Request) -> Result<OutputFormat, Error> {
+    let (name, quality) = format_query(request)?;
+    if let Some(name) = name {
+        return OutputFormat::from_name(&name, quality).map_err(Error::Validation);
+    }
+    let quality = check_quality(quality).map_err(Error::Validation)?;
+
+    let accept = match request.accept() {
+        Some(accept) => accept,
+        None => return Ok(OutputFormat::default()),
+    };
+    by_preference(accept)
+        .iter()
+        .filter_map(|media| {
+            OutputFormat::from_media_type(media.top().as_str(), media.sub().as_str(), quality)
+        })
+        .next()
+        .ok_or_else(|| {
+            Error::NotAcceptable(format!(
+                "can't produce {}, only image/png, image/jpeg, image/bmp, \
+                 image/x-portable-pixmap or application/octet-stream",
+                accept
+            ))
+        })
+}
+
+/// Picks the stream format from the `format` and `quality` query parameters or, without
+/// `format`, from the first media type in `Accept` that can be produced. No `Accept` means MJPEG.
+impl<'a, 'r> FromRequest<'a, 'r> for StreamFormat {
+    type Error = Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<StreamFormat, Error> {
+        match negotiate_stream(request) {
+            Ok(format) => Outcome::Success(format),
+            Err(err) => Outcome::Failure((err.status(), err)),
+        }
+    }
+}
+
+fn negotiate_stream(request: &Request) -> Result<StreamFormat, Error> {
+    let (name, quality) = format_query(request)?;
+    if let Some(name) = name {
+        return StreamFormat::from_name(&name, quality).map_err(Error::Validation);
+    }
+    let quality = check_quality(quality).map_err(Error::Validation)?;
+
+    let accept = match request.accept() {
+        Some(accept) => accept,
+        None => return Ok(StreamFormat::Mjpeg { quality }),
+    };
+    by_preference(accept)
+        .iter()
+        .filter_map(|media| {
+            StreamFormat::from_media_type(media.top().as_str(), media.sub().as_str(), quality)
+        })
+        .next()
+        .ok_or_else(|| {
+            Error::NotAcceptable(format!(
+                "can't produce {}, only multipart/x-mixed-replace or text/event-stream",
+                accept
+            ))
+        })
+}
+
+/// Picks the animation format from the `format` query parameter or, without it, from the first
+/// media type in `Accept` that can be produced. No `Accept` means GIF.
+impl<'a, 'r> FromRequest<'a, 'r> for AnimationFormat {
+    type Error = Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<AnimationFormat, Error> {
+        match negotiate_animation(request) {
+            Ok(format) => Outcome::Success(format),
+            Err(err) => Outcome::Failure((err.status(), err)),
+        }
+    }
+}
+
+fn negotiate_animation(request: &Request) -> Result<AnimationFormat, Error> {
+    for (key, value) in FormItems::from(request.uri().query().unwrap_or("")) {
+        if key.as_str() == "format" {
+            let name = value
+                .url_decode()
+                .map_err(|_| Error::BadRequest("`format` is not valid UTF-8".to_string()))?;
+            return AnimationFormat::from_name(&name).map_err(Error::Validation);
+        }
+    }
+
+    let accept = match request.accept() {
+        Some(accept) => accept,
+        None => return Ok(AnimationFormat::default()),
+    };
+    by_preference(accept)
+        .iter()
+        .filter_map(|media| {
+            AnimationFormat::from_media_type(media.top().as_str(), media.sub().as_str())
+        })
+        .next()
+        .ok_or_else(|| {
+            Error::NotAcceptable(format!(
+                "can't produce {}, only image/gif or application/zip",
+                accept
+            ))
+        })
+}
+
+/// The `format` and `quality` query parameters.
+fn format_query(request: &Request) -> Result<(Option<String>, Option<u8>), Error> {
+    let mut name = None;
+    let mut quality = None;
+    for (key, value) in FormItems::from(request.uri().query().unwrap_or("")) {
+        let value = value
+            .url_decode()
+            .map_err(|_| Error::BadRequest(format!("`{}` is not valid UTF-8", key)))?;
+        match key.as_str() {
+            "format" => name = Some(value),
+            "quality" => {
+                let parsed = value.parse::<u8>().map_err(|_| {
+                    Error::Validation("`quality` must be between 1 and 100".to_string())
+                })?;
+                quality = Some(parsed);
+            }
+            _ => {}
+        }
+    }
+    Ok((name, quality))
+}
+
+/// The media types of `accept` that aren't refused with `q=0`, most wanted first.
+fn by_preference(accept: &Accept) -> Vec<&QMediaType> {
+    let mut media_types: Vec<_> = accept
+        .iter()
+        .filter(|media| media.weight_or(1.0) > 0.0)
+        .collect();
+    // Stable, so equally weighted types keep the client's order.
+    media_types.sort_by(|a, b| b.weight_or(1.0).partial_cmp(&a.weight_or(1.0)).unwrap());
+    media_types
+}
+
+#[post("/shit", format = "application/json", data = "<request>")]
+pub fn render(
+    request: Result<Json<RenderRequest>, SerdeError>,
+    format: Result<OutputFormat, Error>,
+    defaults: State<RenderConfig>,
+    context: State<Arc<GpuContext>>,
+    queue: State<Arc<JobQueue>>,
+) -> Result<Accepted, Error> {
+    let format = format?;
+    let mut request = request?.into_inner();
+    request.import_model().map_err(Error::Validation)?;
+    request.validate().map_err(Error::Validation)?;
+    let (textures, dimensions, device) = prepare(&request, &defaults, &context)?;
+    jobs::submit(
+        &queue,
+        Work::Render {
+            request,
+            textures,
+            dimensions,
+            device,
+            format,
+        },
+    )
+}
+
+/// Renders the scene of the body `frames` times and answers with an animated GIF or a zip of
+/// PNGs, see `negotiate_animation`.
+#[post("/shit/animation", format = "application/json", data = "<request>")]
+pub fn animation(
+    request: Result<Json<AnimationRequest>, SerdeError>,
+    format: Result<AnimationFormat, Error>,
+    defaults: State<RenderConfig>,
+    context: State<Arc<GpuContext>>,
+    queue: State<Arc<JobQueue>>,
+) -> Result<Accepted, Error> {
+    let format = format?;
+    let mut request = request?.into_inner();
+    request.scene.import_model().map_err(Error::Validation)?;
+    request.validate().map_err(Error::Validation)?;
+    let (textures, dimensions, device) = prepare(&request.scene, &defaults, &context)?;
+    format.check_dimensions(dimensions).map_err(Error::Validation)?;
+    jobs::submit(
+        &queue,
+        Work::Animation {
+            request,
+            textures,
+            dimensions,
+            device,
+            format,
+        },
+    )
+}
+
+/// Plays the animation of the body over and over as a live stream of JPEGs, for as long as the
+/// client keeps reading or until `streams.timeout`. The stream is served right away instead of
+/// through the job queue, and keeps a Rocket worker busy while it lasts.
+#[post("/shit/stream", format = "application/json", data = "<request>")]
+pub fn stream(
+    request: Result<Json<AnimationRequest>, SerdeError>,
+    format: Result<StreamFormat, Error>,
+    defaults: State<RenderConfig>,
+    context: State<Arc<GpuContext>>,
+    streams: State<Arc<Streams>>,
+) -> Result<FrameStream, Error> {
+    let format = format?;
+    let mut request = request?.into_inner();
+    request.scene.import_model().map_err(Error::Validation)?;
+    request.validate().map_err(Error::Validation)?;
+    let (textures, dimensions, device) = prepare(&request.scene, &defaults, &context)?;
+    let slot = Streams::acquire(&streams).ok_or_else(|| {
+        Error::Unavailable("too many streams are being served, try again later".to_string())
+    })?;
+    let control = JobControl::new(streams.timeout);
+    let scene = Scene::prepare(&context, &request.scene, &textures, &device, &control)?;
+    Ok(FrameStream::animation(scene, request, dimensions, format, control, slot))
+}
+
+impl<'r> Responder<'r> for FrameStream {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(self.content_type())
+            .raw_header("Cache-Control", "no-cache")
+            .streamed_body(self)
+            .ok()
+    }
+}
+
+/// Checks a validated request against the configured and device limits, returning its decoded
+/// textures, the image size and the device to render on.
+pub fn prepare(
+    request: &RenderRequest,
+    defaults: &RenderConfig,
+    context: &GpuContext,
+) -> Result<(Vec<RgbaImage>, [u32; 2], DeviceSelector), Error> {
+    let dimensions = request.dimensions([defaults.width, defaults.height]);
+    if dimensions[0] > defaults.max_width || dimensions[1] > defaults.max_height {
+        return Err(Error::Validation(format!(
+            "images are limited to {}x{}",
+            defaults.max_width, defaults.max_height
+        )));
+    }
+    let device = request
+        .device
+        .clone()
+        .unwrap_or_else(|| defaults.device.clone());
+    let (gpu, _) = context.device(&device, QueueKind::Graphics)?;
+    let max_texture = render::max_texture_size(&gpu, [defaults.max_width, defaults.max_height]);
+    let textures = request
+        .decode_textures(max_texture)
+        .map_err(Error::Validation)?;
+    render::check_limits(&gpu, request, dimensions).map_err(Error::Validation)?;
+    Ok((textures, dimensions, device))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preferred(accept: &str) -> Vec<String> {
+        let accept: Accept = accept.parse().unwrap();
+        by_preference(&accept)
+            .iter()
+            .map(|media| format!("{}/{}", media.top().as_str(), media.sub().as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn heavier_media_types_come_first() {
+        assert_eq!(
+            preferred("image/png;q=0.5, image/jpeg, image/bmp;q=0.8"),
+            vec!["image/jpeg", "image/bmp", "image/png"]
+        );
+    }
+
+    #[test]
+    fn equal_weights_keep_the_client_order() {
+        assert_eq!(
+            preferred("image/bmp, image/jpeg;q=0.9, image/png, image/gif;q=0.9"),
+            vec!["image/bmp", "image/png", "image/jpeg", "image/gif"]
+        );
+    }
+
+    #[test]
+    fn refused_media_types_are_dropped() {
+        assert_eq!(preferred("image/png;q=0, */*;q=0.1"), vec!["*/*"]);
+        assert!(preferred("image/png;q=0").is_empty());
+    }
+}
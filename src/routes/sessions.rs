@@ -0,0 +1,106 @@
+use rocket::request::Request;
+use rocket::response::{self, status, Content, Responder};
+use rocket::State;
+use rocket_contrib::{Json, SerdeError};
+use std::sync::Arc;
+
+use super::render::prepare;
+use crate::config::RenderConfig;
+use crate::context::GpuContext;
+use crate::error::Error;
+use crate::render::{OutputFormat, RenderRequest, Scene};
+use crate::sessions::{SessionChanges, SessionInfo, Sessions};
+
+fn not_found(session_id: &str) -> Error {
+    Error::NotFound(format!("session {} not found", session_id))
+}
+
+/// Uploads the scene of the body to its device and keeps it there, so that frames can be drawn
+/// from it until the session is closed.
+#[post("/", format = "application/json", data = "<request>")]
+pub fn create(
+    request: Result<Json<RenderRequest>, SerdeError>,
+    defaults: State<RenderConfig>,
+    context: State<Arc<GpuContext>>,
+    sessions: State<Arc<Sessions>>,
+) -> Result<status::Created<Json<SessionInfo>>, Error> {
+    let mut request = request?.into_inner();
+    request.import_model().map_err(Error::Validation)?;
+    request.validate().map_err(Error::Validation)?;
+    let (textures, dimensions, device) = prepare(&request, &defaults, &context)?;
+    // The place is given back if preparing the scene fails.
+    let slot = Sessions::reserve(&sessions).ok_or_else(|| {
+        Error::Unavailable("too many sessions are open, try again later".to_string())
+    })?;
+    let scene = Scene::prepare(&context, &request, &textures, &device, &sessions.control())?;
+    let info = sessions.open(slot, scene, &request, dimensions);
+    Ok(status::Created(format!("/sessions/{}", info.id), Some(Json(info))))
+}
+
+#[get("/<session_id>")]
+pub fn get(session_id: String, sessions: State<Arc<Sessions>>) -> Result<Json<SessionInfo>, Error> {
+    let session = sessions.get(&session_id).ok_or_else(|| not_found(&session_id))?;
+    let info = session.lock().unwrap().info();
+    Ok(Json(info))
+}
+
+/// Moves the camera, the scene or its meshes, or changes the projection, clear color or mesh
+/// colors, for the frames drawn from now on.
+#[patch("/<session_id>", format = "application/json", data = "<changes>")]
+pub fn update(
+    session_id: String,
+    changes: Result<Json<SessionChanges>, SerdeError>,
+    sessions: State<Arc<Sessions>>,
+) -> Result<Json<SessionInfo>, Error> {
+    let changes = changes?.into_inner();
+    let session = sessions.get(&session_id).ok_or_else(|| not_found(&session_id))?;
+    let mut session = session.lock().unwrap();
+    session.update(changes).map_err(Error::Validation)?;
+    Ok(Json(session.info()))
+}
+
+/// Draws the session as it currently stands, right away rather than through the job queue.
+#[get("/<session_id>/frame")]
+pub fn frame(
+    session_id: String,
+    format: Result<OutputFormat, Error>,
+    sessions: State<Arc<Sessions>>,
+) -> Result<Frame, Error> {
+    let format = format?;
+    let session = sessions.get(&session_id).ok_or_else(|| not_found(&session_id))?;
+    let image = session.lock().unwrap().frame(&sessions.control())?;
+    Ok(Frame {
+        format,
+        headers: format.headers(&image),
+        body: format.encode(&image)?,
+    })
+}
+
+/// Closes the session, freeing what it holds on its device.
+#[delete("/<session_id>")]
+pub fn delete(
+    session_id: String,
+    sessions: State<Arc<Sessions>>,
+) -> Result<status::NoContent, Error> {
+    if !sessions.close(&session_id) {
+        return Err(not_found(&session_id));
+    }
+    Ok(status::NoContent)
+}
+
+/// A frame of a session, encoded as the client asked.
+pub struct Frame {
+    format: OutputFormat,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl<'r> Responder<'r> for Frame {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut response = Content(self.format.content_type(), self.body).respond_to(request)?;
+        for (name, value) in self.headers {
+            response.set_raw_header(name, value);
+        }
+        Ok(response)
+    }
+}
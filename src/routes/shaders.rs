@@ -0,0 +1,50 @@
+use rocket::http::ContentType;
+use rocket::response::Content;
+use rocket::State;
+use rocket_contrib::{Json, JsonValue, SerdeError};
+
+use crate::error::Error;
+use crate::shaders::{ShaderCache, ShaderStage};
+
+#[derive(Deserialize)]
+pub struct ShaderSubmission {
+    pub stage: ShaderStage,
+    pub source: String,
+}
+
+#[post("/", format = "application/json", data = "<submission>")]
+pub fn compile(
+    submission: Result<Json<ShaderSubmission>, SerdeError>,
+    cache: State<ShaderCache>,
+) -> Result<JsonValue, Error> {
+    let submission = submission?.into_inner();
+
+    let (compilation, cached) = cache.compile(submission.stage, &submission.source);
+    if !compilation.succeeded() {
+        return Err(Error::Shader(compilation));
+    }
+
+    Ok(json!({
+        "hash": compilation.hash,
+        "stage": compilation.stage,
+        "cached": cached,
+        "size": compilation.size(),
+        "diagnostics": compilation.diagnostics,
+    }))
+}
+
+/// Downloads the SPIR-V of a previously submitted shader.
+#[get("/<hash>")]
+pub fn get(hash: String, cache: State<ShaderCache>) -> Result<Content<Vec<u8>>, Error> {
+    let compilation = cache
+        .get(&hash)
+        .ok_or_else(|| Error::NotFound(format!("no shader with hash {} in the cache", hash)))?;
+
+    match compilation.spirv {
+        Some(ref spirv) => Ok(Content(
+            ContentType::new("application", "spirv"),
+            spirv.clone(),
+        )),
+        None => Err(Error::Shader(compilation.clone())),
+    }
+}
@@ -1,3 +1,18 @@
+table! {
+    jobs (id) {
+        id -> Int8,
+        kind -> Varchar,
+        request -> Jsonb,
+        status -> Varchar,
+        device -> Nullable<Varchar>,
+        started_at -> Nullable<Timestamp>,
+        finished_at -> Nullable<Timestamp>,
+        error -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
 table! {
     posts (id) {
         id -> Int4,
@@ -6,3 +21,8 @@ table! {
         published -> Bool,
     }
 }
+
+allow_tables_to_appear_in_same_query!(
+    jobs,
+    posts,
+);
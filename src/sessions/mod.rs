@@ -0,0 +1,316 @@
+use image::RgbaImage;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::SessionsConfig;
+use crate::jobs::JobControl;
+use crate::render::mesh::is_color;
+use crate::render::transform::{self, Camera, Projection, Transform};
+use crate::render::{RenderError, RenderRequest, Scene};
+
+/// How often idle sessions are looked for, and so how late they may be closed.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Random and long enough not to be guessed, since it is all that gives access to a session.
+pub type SessionId = String;
+
+/// A scene kept on its device between requests, along with where it is looked at from.
+pub struct Session {
+    id: SessionId,
+    scene: Scene,
+    dimensions: [u32; 2],
+    camera: Option<Camera>,
+    projection: Option<Projection>,
+    /// Applied to the whole scene, before the transforms of the meshes.
+    transform: Option<Transform>,
+    clear_color: [f32; 4],
+    /// The last frame drawn, dropped by every change.
+    frame: Option<Arc<RgbaImage>>,
+    idle_timeout: Duration,
+}
+
+/// What `GET /sessions/<id>` reports about a session.
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    /// Name of the device the session's resources live on.
+    pub device: String,
+    pub width: u32,
+    pub height: u32,
+    pub meshes: usize,
+    pub camera: Option<Camera>,
+    pub projection: Option<Projection>,
+    pub transform: Option<Transform>,
+    pub clear_color: [f32; 4],
+    /// Where the current frame can be fetched.
+    pub frame: String,
+    /// Seconds without requests after which the session is closed.
+    pub idle_timeout: u64,
+}
+
+/// The body of `PATCH /sessions/<id>`. Fields left out stay as they are.
+#[derive(Deserialize)]
+pub struct SessionChanges {
+    #[serde(default)]
+    pub camera: Option<Camera>,
+    #[serde(default)]
+    pub projection: Option<Projection>,
+    #[serde(default)]
+    pub transform: Option<Transform>,
+    #[serde(default)]
+    pub clear_color: Option<[f32; 4]>,
+    #[serde(default)]
+    pub meshes: Vec<MeshChanges>,
+}
+
+/// New values for the mesh at `index` in the request that opened the session.
+#[derive(Deserialize)]
+pub struct MeshChanges {
+    pub index: usize,
+    #[serde(default)]
+    pub transform: Option<Transform>,
+    /// The material color, multiplied with the vertex colors.
+    #[serde(default)]
+    pub color: Option<[f32; 4]>,
+}
+
+impl SessionChanges {
+    fn validate(&self, meshes: usize) -> Result<(), String> {
+        if let Some(ref camera) = self.camera {
+            camera.validate()?;
+        }
+        if let Some(ref projection) = self.projection {
+            projection.validate()?;
+        }
+        if let Some(ref transform) = self.transform {
+            transform.validate()?;
+        }
+        if let Some(ref clear_color) = self.clear_color {
+            if !is_color(clear_color) {
+                return Err("`clear_color` components must be between 0 and 1".to_string());
+            }
+        }
+        for changes in &self.meshes {
+            if changes.index >= meshes {
+                return Err(format!(
+                    "mesh {} is out of range for {} meshes",
+                    changes.index, meshes
+                ));
+            }
+            if let Some(ref transform) = changes.transform {
+                transform
+                    .validate()
+                    .map_err(|e| format!("mesh {}: {}", changes.index, e))?;
+            }
+            if let Some(ref color) = changes.color {
+                if !is_color(color) {
+                    return Err(format!(
+                        "mesh {}: `color` components must be between 0 and 1",
+                        changes.index
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Session {
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id.clone(),
+            device: self.scene.gpu.device.physical_device().name().to_string(),
+            width: self.dimensions[0],
+            height: self.dimensions[1],
+            meshes: self.scene.mesh_count(),
+            camera: self.camera.clone(),
+            projection: self.projection.clone(),
+            transform: self.transform.clone(),
+            clear_color: self.clear_color,
+            frame: format!("/sessions/{}/frame", self.id),
+            idle_timeout: self.idle_timeout.as_secs(),
+        }
+    }
+
+    /// Applies `changes` to the frames drawn from now on, all of them or, when any is invalid,
+    /// none.
+    pub fn update(&mut self, changes: SessionChanges) -> Result<(), String> {
+        changes.validate(self.scene.mesh_count())?;
+        if changes.camera.is_some() {
+            self.camera = changes.camera;
+        }
+        if changes.projection.is_some() {
+            self.projection = changes.projection;
+        }
+        if changes.transform.is_some() {
+            self.transform = changes.transform;
+        }
+        if let Some(clear_color) = changes.clear_color {
+            self.clear_color = clear_color;
+        }
+        for mesh in &changes.meshes {
+            self.scene
+                .update_mesh(mesh.index, mesh.transform.as_ref(), mesh.color)?;
+        }
+        self.frame = None;
+        Ok(())
+    }
+
+    /// The frame as the session currently stands, only drawn again after a change.
+    pub fn frame(&mut self, control: &JobControl) -> Result<Arc<RgbaImage>, RenderError> {
+        if let Some(ref frame) = self.frame {
+            return Ok(frame.clone());
+        }
+        let view_projection = transform::view_projection(
+            self.camera.as_ref(),
+            self.projection.as_ref(),
+            self.dimensions,
+        ) * transform::model(self.transform.as_ref());
+        let image = self
+            .scene
+            .draw(self.dimensions, self.clear_color, view_projection, control)?;
+        let frame = Arc::new(image);
+        self.frame = Some(frame.clone());
+        Ok(frame)
+    }
+}
+
+/// Open sessions, closed on request or after `idle_timeout` without requests, which frees what
+/// they hold on their device.
+pub struct Sessions {
+    sessions: Mutex<HashMap<SessionId, Entry>>,
+    /// Open sessions plus the places reserved for sessions being prepared.
+    taken: AtomicUsize,
+    max: usize,
+    idle_timeout: Duration,
+    /// Longest a frame may take to draw.
+    frame_timeout: Duration,
+}
+
+struct Entry {
+    session: Arc<Mutex<Session>>,
+    last_used: Instant,
+}
+
+/// A place among the open sessions, held while the scene of a new session is prepared. It is
+/// given back when dropped, unless the session was opened in it.
+pub struct SessionSlot {
+    sessions: Arc<Sessions>,
+    opened: bool,
+}
+
+impl Sessions {
+    /// Creates the store and starts the thread closing idle sessions, which runs for the
+    /// lifetime of the server. Frames give up after `frame_timeout`.
+    pub fn start(config: &SessionsConfig, frame_timeout: Duration) -> Arc<Sessions> {
+        let sessions = Arc::new(Sessions {
+            sessions: Mutex::new(HashMap::new()),
+            taken: AtomicUsize::new(0),
+            max: config.max,
+            idle_timeout: Duration::from_secs(config.idle_timeout),
+            frame_timeout,
+        });
+
+        let evicting = sessions.clone();
+        thread::Builder::new()
+            .name("session-eviction".to_string())
+            .spawn(move || loop {
+                thread::sleep(EVICTION_INTERVAL);
+                evicting.evict_idle();
+            })
+            .expect("failed to spawn session eviction thread");
+
+        sessions
+    }
+
+    /// Takes a place for a new session before its scene is prepared, or returns `None` when
+    /// `sessions.max` sessions are already open or being prepared.
+    pub fn reserve(sessions: &Arc<Sessions>) -> Option<SessionSlot> {
+        if sessions.taken.fetch_add(1, Ordering::SeqCst) >= sessions.max {
+            sessions.taken.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(SessionSlot {
+            sessions: sessions.clone(),
+            opened: false,
+        })
+    }
+
+    /// Stops the wait for the GPU when drawing a frame or preparing a scene takes too long.
+    pub fn control(&self) -> JobControl {
+        JobControl::new(self.frame_timeout)
+    }
+
+    /// Opens a session in `slot`, drawing `scene`, as `request` describes it, into frames of
+    /// `dimensions`.
+    pub fn open(
+        &self,
+        mut slot: SessionSlot,
+        scene: Scene,
+        request: &RenderRequest,
+        dimensions: [u32; 2],
+    ) -> SessionInfo {
+        let id = format!("{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>());
+        let session = Session {
+            id: id.clone(),
+            scene,
+            dimensions,
+            camera: request.camera.clone(),
+            projection: request.projection.clone(),
+            transform: None,
+            clear_color: request.clear_color(),
+            frame: None,
+            idle_timeout: self.idle_timeout,
+        };
+        let info = session.info();
+        self.sessions.lock().unwrap().insert(
+            id,
+            Entry {
+                session: Arc::new(Mutex::new(session)),
+                last_used: Instant::now(),
+            },
+        );
+        slot.opened = true;
+        info
+    }
+
+    /// The session `id`, which counts as a use of it.
+    pub fn get(&self, id: &str) -> Option<Arc<Mutex<Session>>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let entry = sessions.get_mut(id)?;
+        entry.last_used = Instant::now();
+        Some(entry.session.clone())
+    }
+
+    /// Closes the session `id`, returning whether it was open. Requests using it finish first.
+    pub fn close(&self, id: &str) -> bool {
+        let closed = self.sessions.lock().unwrap().remove(id).is_some();
+        if closed {
+            self.taken.fetch_sub(1, Ordering::SeqCst);
+        }
+        closed
+    }
+
+    /// Closes the sessions that have been idle for `idle_timeout`. Sessions that requests are
+    /// still using count as busy however long ago they were asked for.
+    fn evict_idle(&self) {
+        let idle_timeout = self.idle_timeout;
+        let mut sessions = self.sessions.lock().unwrap();
+        let open = sessions.len();
+        sessions.retain(|_, entry| {
+            Arc::strong_count(&entry.session) > 1 || entry.last_used.elapsed() < idle_timeout
+        });
+        self.taken.fetch_sub(open - sessions.len(), Ordering::SeqCst);
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        if !self.opened {
+            self.sessions.taken.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
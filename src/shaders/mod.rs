@@ -0,0 +1,293 @@
+use glsl_to_spirv::ShaderType;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+/// Every SPIR-V module starts with this word.
+pub(crate) const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+    Geometry,
+    TessellationControl,
+    TessellationEvaluation,
+    Compute,
+}
+
+impl ShaderStage {
+    fn shader_type(self) -> ShaderType {
+        match self {
+            ShaderStage::Vertex => ShaderType::Vertex,
+            ShaderStage::Fragment => ShaderType::Fragment,
+            ShaderStage::Geometry => ShaderType::Geometry,
+            ShaderStage::TessellationControl => ShaderType::TessellationControl,
+            ShaderStage::TessellationEvaluation => ShaderType::TessellationEvaluation,
+            ShaderStage::Compute => ShaderType::Compute,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ShaderStage::Vertex => "vertex",
+            ShaderStage::Fragment => "fragment",
+            ShaderStage::Geometry => "geometry",
+            ShaderStage::TessellationControl => "tessellation_control",
+            ShaderStage::TessellationEvaluation => "tessellation_evaluation",
+            ShaderStage::Compute => "compute",
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// One message from glslang, with the source line it refers to when it names one.
+#[derive(Serialize, Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub line: Option<u32>,
+    pub message: String,
+}
+
+/// The outcome of compiling one shader source, successful or not.
+#[derive(Serialize, Debug)]
+pub struct Compilation {
+    /// SHA-256 of the stage and source, which is also the cache key.
+    pub hash: String,
+    pub stage: ShaderStage,
+    #[serde(skip)]
+    pub spirv: Option<Vec<u8>>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Compilation {
+    pub fn succeeded(&self) -> bool {
+        self.spirv.is_some()
+    }
+
+    /// Size of the SPIR-V module in bytes, zero when compilation failed.
+    pub fn size(&self) -> usize {
+        self.spirv.as_ref().map_or(0, Vec::len)
+    }
+}
+
+/// Identifies a stage and source pair.
+pub fn content_hash(stage: ShaderStage, source: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(stage.name().as_bytes());
+    hasher.input(b"\0");
+    hasher.input(source.as_bytes());
+    format!("{:x}", hasher.result())
+}
+
+/// Compiles GLSL to SPIR-V with glslang, which also validates the result against the Vulkan
+/// rules for the stage.
+pub fn compile(stage: ShaderStage, source: &str) -> Compilation {
+    let hash = content_hash(stage, source);
+    let (spirv, log) = match glsl_to_spirv::compile(source, stage.shader_type()) {
+        Ok(mut output) => {
+            let mut spirv = Vec::new();
+            match output.read_to_end(&mut spirv) {
+                Ok(_) => (Some(spirv), String::new()),
+                Err(err) => (None, format!("ERROR: failed to read SPIR-V output: {}", err)),
+            }
+        }
+        Err(log) => (None, log),
+    };
+
+    let mut diagnostics = parse_diagnostics(&log);
+    let spirv = spirv.and_then(|spirv| {
+        if spirv.len() >= 4 && spirv.len() % 4 == 0 && le_words(&spirv[..4])[0] == SPIRV_MAGIC {
+            Some(spirv)
+        } else {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                line: None,
+                message: "glslang produced an invalid SPIR-V module".to_string(),
+            });
+            None
+        }
+    });
+
+    // Make sure a failure always comes with at least one message.
+    if spirv.is_none() && !diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            line: None,
+            message: log.trim().to_string(),
+        });
+    }
+
+    Compilation {
+        hash,
+        stage,
+        spirv,
+        diagnostics,
+    }
+}
+
+/// Reads `bytes`, whose length must be a multiple of 4, as little-endian 32-bit words, the way
+/// SPIR-V modules are stored.
+pub(crate) fn le_words(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks(4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+/// Picks the `ERROR:` and `WARNING:` lines out of glslang's output.
+///
+/// glslang prefixes located messages with `<file>:<line>: `, where the file is either the
+/// temporary path the source was written to or a source string number, so only the line is
+/// kept. Summary lines such as `ERROR: 1 compilation errors.  No code generated.` are dropped.
+fn parse_diagnostics(log: &str) -> Vec<Diagnostic> {
+    log.lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let (severity, rest) = if line.starts_with("ERROR: ") {
+                (Severity::Error, &line["ERROR: ".len()..])
+            } else if line.starts_with("WARNING: ") {
+                (Severity::Warning, &line["WARNING: ".len()..])
+            } else {
+                return None;
+            };
+
+            let (number, message) = split_location(rest);
+            if number.is_none() && message.contains("compilation errors") {
+                return None;
+            }
+            Some(Diagnostic {
+                severity,
+                line: number,
+                message: message.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Splits `<file>:<line>: <message>` into the line number and the message.
+fn split_location(rest: &str) -> (Option<u32>, &str) {
+    let mut search = 0;
+    while let Some(colon) = rest[search..].find(':') {
+        let start = search + colon + 1;
+        let end = rest[start..]
+            .find(|c: char| !c.is_ascii_digit())
+            .map_or(rest.len(), |offset| start + offset);
+        if end > start && rest[end..].starts_with(": ") {
+            if let Ok(number) = rest[start..end].parse() {
+                return (Some(number), rest[end + 2..].trim());
+            }
+        }
+        search = start;
+    }
+    (None, rest.trim())
+}
+
+/// Compilations keyed by `content_hash`, so resubmitting a shader doesn't run glslang again.
+///
+/// Failed compilations are kept too, since they fail the same way every time. Once `capacity`
+/// entries are stored the oldest one is dropped.
+pub struct ShaderCache {
+    capacity: usize,
+    entries: Mutex<CacheEntries>,
+}
+
+struct CacheEntries {
+    by_hash: HashMap<String, Arc<Compilation>>,
+    order: VecDeque<String>,
+}
+
+impl ShaderCache {
+    pub fn new(capacity: usize) -> ShaderCache {
+        ShaderCache {
+            capacity,
+            entries: Mutex::new(CacheEntries {
+                by_hash: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn get(&self, hash: &str) -> Option<Arc<Compilation>> {
+        self.entries.lock().unwrap().by_hash.get(hash).cloned()
+    }
+
+    /// Returns the compilation of `source`, and whether it came from the cache.
+    pub fn compile(&self, stage: ShaderStage, source: &str) -> (Arc<Compilation>, bool) {
+        if let Some(compilation) = self.get(&content_hash(stage, source)) {
+            return (compilation, true);
+        }
+
+        // glslang runs as a separate process and can take a while, so the lock is not held
+        // meanwhile. Two identical submissions racing each other both compile, which is harmless.
+        let compilation = Arc::new(compile(stage, source));
+
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.by_hash.contains_key(&compilation.hash) {
+            while entries.order.len() >= self.capacity.max(1) {
+                if let Some(oldest) = entries.order.pop_front() {
+                    entries.by_hash.remove(&oldest);
+                }
+            }
+            entries.order.push_back(compilation.hash.clone());
+            entries
+                .by_hash
+                .insert(compilation.hash.clone(), compilation.clone());
+        }
+        (compilation, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locations_are_split_from_messages() {
+        assert_eq!(
+            split_location("0:12: 'foo' : undeclared identifier"),
+            (Some(12), "'foo' : undeclared identifier")
+        );
+        assert_eq!(
+            split_location("C:\\tmp\\shader.comp:3: missing ';'"),
+            (Some(3), "missing ';'")
+        );
+        assert_eq!(split_location(" linking failed "), (None, "linking failed"));
+        assert_eq!(split_location("0:x: not a line"), (None, "0:x: not a line"));
+    }
+
+    #[test]
+    fn errors_and_warnings_are_parsed() {
+        let log = "shader.comp\n\
+                   WARNING: 0:2: '' : unused extension\n\
+                   ERROR: 0:5: 'x' : undeclared identifier\n\
+                   ERROR: 1 compilation errors.  No code generated.\n";
+        let diagnostics = parse_diagnostics(log);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].line, Some(2));
+        assert_eq!(diagnostics[0].message, "'' : unused extension");
+        assert_eq!(diagnostics[1].severity, Severity::Error);
+        assert_eq!(diagnostics[1].line, Some(5));
+        assert_eq!(diagnostics[1].message, "'x' : undeclared identifier");
+    }
+
+    #[test]
+    fn messages_without_a_line_are_kept() {
+        let diagnostics = parse_diagnostics("ERROR: Linking compute stage: Missing entry point");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, None);
+        assert_eq!(
+            diagnostics[0].message,
+            "Linking compute stage: Missing entry point"
+        );
+    }
+}